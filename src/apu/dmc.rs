@@ -0,0 +1,214 @@
+use std::io;
+use std::io::prelude::*;
+
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_DMC
+// NTSC timer periods, in CPU cycles
+const RATE_TABLE: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub struct Dmc {
+    irq_enabled: bool,
+    looping: bool,
+    timer: u16,
+    timer_period: u16,
+    output_level: u8, // 0-127
+
+    // Memory reader
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+
+    // Output unit
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new() -> Dmc {
+        Dmc {
+            irq_enabled: false,
+            looping: false,
+            timer: 0,
+            timer_period: RATE_TABLE[0],
+            output_level: 0,
+
+            sample_address: 0xC000,
+            sample_length: 1,
+            current_address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+
+            irq: false,
+        }
+    }
+
+    // register is 0-3, for $4010-$4013
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // IL-- RRRR
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping = value & 0x40 != 0;
+                self.timer_period = RATE_TABLE[value as usize & 0x0F];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            // -DDD DDDD
+            1 => self.output_level = value & 0x7F,
+            // Sample address %11AAAAAA.AA000000
+            2 => self.sample_address = 0xC000 | ((value as u16) << 6),
+            // Sample length %LLLL.LLLL0001
+            _ => self.sample_length = ((value as u16) << 4) | 1,
+        }
+    }
+
+    // Bit 4 of $4015. Writing it also acknowledges the DMC IRQ.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // Whether there are still bytes to play
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // The address of the next sample byte, when the buffer needs refilling
+    pub fn fetch_address(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.bytes_remaining > 0 {
+            Some(self.current_address)
+        } else {
+            None
+        }
+    }
+
+    // Called with the byte read by the DMA from fetch_address()
+    pub fn fill(&mut self, value: u8) {
+        self.buffer = Some(value);
+        self.current_address = if self.current_address == 0xFFFF { 0x8000 } else { self.current_address + 1 };
+        self.bytes_remaining -= 1;
+
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.timer_period - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.output_level <= 125 {
+                    self.output_level += 2;
+                }
+            } else if self.output_level >= 2 {
+                self.output_level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+
+        // Start a new output cycle with whatever is in the buffer
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.output_level
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_bool(writer, self.irq_enabled)?;
+        write_bool(writer, self.looping)?;
+        write_u16(writer, self.timer)?;
+        write_u16(writer, self.timer_period)?;
+        write_u8(writer, self.output_level)?;
+        write_u16(writer, self.sample_address)?;
+        write_u16(writer, self.sample_length)?;
+        write_u16(writer, self.current_address)?;
+        write_u16(writer, self.bytes_remaining)?;
+        write_bool(writer, self.buffer.is_some())?;
+        write_u8(writer, self.buffer.unwrap_or(0))?;
+        write_u8(writer, self.shift)?;
+        write_u8(writer, self.bits_remaining)?;
+        write_bool(writer, self.silence)?;
+        write_bool(writer, self.irq)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.irq_enabled = read_bool(reader)?;
+        self.looping = read_bool(reader)?;
+        self.timer = read_u16(reader)?;
+        let timer_period = read_u16(reader)?;
+        if !RATE_TABLE.contains(&timer_period) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid DMC rate {}", timer_period)));
+        }
+        self.timer_period = timer_period;
+        self.output_level = read_u8(reader)? & 0x7F;
+        self.sample_address = read_u16(reader)?;
+        self.sample_length = read_u16(reader)?;
+        self.current_address = read_u16(reader)?;
+        self.bytes_remaining = read_u16(reader)?;
+        let has_buffer = read_bool(reader)?;
+        let buffer = read_u8(reader)?;
+        self.buffer = if has_buffer { Some(buffer) } else { None };
+        self.shift = read_u8(reader)?;
+        let bits_remaining = read_u8(reader)?;
+        if bits_remaining == 0 || bits_remaining > 8 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid DMC bit count {}", bits_remaining)));
+        }
+        self.bits_remaining = bits_remaining;
+        self.silence = read_bool(reader)?;
+        self.irq = read_bool(reader)?;
+        Ok(())
+    }
+}
+
+impl Default for Dmc {
+    fn default() -> Dmc {
+        Dmc::new()
+    }
+}
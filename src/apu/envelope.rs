@@ -0,0 +1,86 @@
+use std::io;
+use std::io::prelude::*;
+
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_Envelope
+pub struct Envelope {
+    start: bool,
+    looping: bool, // Shared with the length counter halt flag
+    constant: bool,
+    period: u8, // Also the constant volume
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub fn new() -> Envelope {
+        Envelope {
+            start: false,
+            looping: false,
+            constant: false,
+            period: 0,
+            divider: 0,
+            decay: 0,
+        }
+    }
+
+    // --LC VVVV
+    pub fn write_control(&mut self, value: u8) {
+        self.looping = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.period = value & 0x0F;
+    }
+
+    pub fn restart(&mut self) {
+        self.start = true;
+    }
+
+    // Quarter frame
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.period;
+        } else if self.divider == 0 {
+            self.divider = self.period;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        if self.constant { self.period } else { self.decay }
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_bool(writer, self.start)?;
+        write_bool(writer, self.looping)?;
+        write_bool(writer, self.constant)?;
+        write_u8(writer, self.period)?;
+        write_u8(writer, self.divider)?;
+        write_u8(writer, self.decay)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.start = read_bool(reader)?;
+        self.looping = read_bool(reader)?;
+        self.constant = read_bool(reader)?;
+        // 4 bits each, the volume goes straight into the mixer tables
+        self.period = read_u8(reader)? & 0x0F;
+        self.divider = read_u8(reader)? & 0x0F;
+        self.decay = read_u8(reader)? & 0x0F;
+        Ok(())
+    }
+}
+
+impl Default for Envelope {
+    fn default() -> Envelope {
+        Envelope::new()
+    }
+}
@@ -0,0 +1,75 @@
+use std::io;
+use std::io::prelude::*;
+
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_Length_Counter
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+pub struct LengthCounter {
+    enabled: bool,
+    halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn new() -> LengthCounter {
+        LengthCounter {
+            enabled: false,
+            halt: false,
+            counter: 0,
+        }
+    }
+
+    // Clearing the enabled bit in $4015 silences the channel immediately
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn set_halt(&mut self, halt: bool) {
+        self.halt = halt;
+    }
+
+    // Upper 5 bits of the channel's last register
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTH_TABLE[index as usize & 0x1F];
+        }
+    }
+
+    // Half frame
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_bool(writer, self.enabled)?;
+        write_bool(writer, self.halt)?;
+        write_u8(writer, self.counter)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.enabled = read_bool(reader)?;
+        self.halt = read_bool(reader)?;
+        self.counter = read_u8(reader)?;
+        Ok(())
+    }
+}
+
+impl Default for LengthCounter {
+    fn default() -> LengthCounter {
+        LengthCounter::new()
+    }
+}
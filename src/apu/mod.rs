@@ -0,0 +1,322 @@
+use std::cell::RefCell;
+use std::io;
+use std::io::prelude::*;
+use std::path::Path;
+use std::rc::Rc;
+
+use savestate::*;
+
+pub mod dmc;
+pub mod envelope;
+pub mod length_counter;
+pub mod noise;
+pub mod pulse;
+pub mod recorder;
+pub mod resampler;
+pub mod triangle;
+pub mod wav;
+
+use self::dmc::Dmc;
+use self::noise::Noise;
+use self::pulse::Pulse;
+use self::recorder::Recorder;
+use self::triangle::Triangle;
+
+// http://wiki.nesdev.com/w/index.php/APU
+
+// CPU cycles stolen by a DMC sample fetch
+const DMC_DMA_CYCLES: u64 = 4;
+
+// NTSC CPU clock: 21.477272MHz / 12
+pub const CPU_CLOCK_RATE: f64 = 1_789_772.727;
+
+// Sample rate of the WAV recordings
+pub const RECORDING_SAMPLE_RATE: u32 = 48000;
+
+// Receives the APU output at the CPU clock rate (~1.79MHz)
+pub trait AudioSink {
+    fn sample(&mut self, sample: f32);
+}
+
+// Lets the frontend keep a handle on a sink owned by the APU
+impl<T: AudioSink> AudioSink for Rc<RefCell<T>> {
+    fn sample(&mut self, sample: f32) {
+        self.borrow_mut().sample(sample);
+    }
+}
+
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    cycle: u64,
+    frame_cycle: u32, // Position in the frame sequence, in CPU cycles
+    five_step_mode: bool,
+    irq_inhibit: bool,
+    frame_irq: bool,
+
+    sink: Option<Box<dyn AudioSink>>,
+    recorder: Option<Recorder>,
+}
+
+impl Apu {
+    pub fn new() -> Apu {
+        Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::new(),
+            noise: Noise::new(),
+            dmc: Dmc::new(),
+
+            cycle: 0,
+            frame_cycle: 0,
+            five_step_mode: false,
+            irq_inhibit: false,
+            frame_irq: false,
+
+            sink: None,
+            recorder: None,
+        }
+    }
+
+    pub fn set_sink(&mut self, sink: Option<Box<dyn AudioSink>>) {
+        self.sink = sink;
+    }
+
+    // Writes the mixed output to a WAV file at `path` until stop_recording() is called.
+    // With `per_channel`, each channel also gets its own file next to it (e.g. out.noise.wav).
+    pub fn start_recording(&mut self, path: &Path, per_channel: bool) -> io::Result<()> {
+        self.stop_recording()?;
+        self.recorder = Some(Recorder::create(path, RECORDING_SAMPLE_RATE, per_channel)?);
+        Ok(())
+    }
+
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish(),
+            None => Ok(()),
+        }
+    }
+
+    pub fn recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    // $4015 Read status: IF-D NT21
+    pub fn read_status(&mut self) -> u8 {
+        let mut status = 0;
+        if self.pulse1.length.active() { status |= 0x01 }
+        if self.pulse2.length.active() { status |= 0x02 }
+        if self.triangle.length.active() { status |= 0x04 }
+        if self.noise.length.active() { status |= 0x08 }
+        if self.dmc.active() { status |= 0x10 }
+        if self.frame_irq { status |= 0x40 }
+        if self.dmc.irq { status |= 0x80 }
+
+        self.frame_irq = false;
+        status
+    }
+
+    pub fn store(&mut self, address: u16, value: u8) {
+        match address {
+            0x4000..=0x4003 => self.pulse1.write(address - 0x4000, value),
+            0x4004..=0x4007 => self.pulse2.write(address - 0x4004, value),
+            0x4008..=0x400B => self.triangle.write(address - 0x4008, value),
+            0x400C..=0x400F => self.noise.write(address - 0x400C, value),
+            0x4010..=0x4013 => self.dmc.write(address - 0x4010, value),
+            // ---D NT21
+            0x4015 => {
+                self.pulse1.length.set_enabled(value & 0x01 != 0);
+                self.pulse2.length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => self.write_frame_counter(value),
+            _ => ()
+        }
+    }
+
+    // $4017 Write to the frame counter: MI-- ----
+    // The sequencer is reset 3 or 4 cycles after the write on hardware, we do it right away.
+    fn write_frame_counter(&mut self, value: u8) {
+        self.five_step_mode = value & 0x80 != 0;
+        self.irq_inhibit = value & 0x40 != 0;
+        if self.irq_inhibit {
+            self.frame_irq = false;
+        }
+
+        self.frame_cycle = 0;
+        if self.five_step_mode {
+            self.clock_quarter_frame();
+            self.clock_half_frame();
+        }
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // Runs the APU for the given number of CPU cycles. DMC sample fetches go through `read`
+    // and stall the CPU: returns the number of stolen cycles, which the APU also runs through.
+    // http://wiki.nesdev.com/w/index.php/APU_DMC#Memory_reader
+    pub fn step<F: FnMut(u16) -> u8>(&mut self, cycles: u64, mut read: F) -> u64 {
+        let mut remaining = cycles;
+        let mut stall = 0;
+
+        while remaining > 0 {
+            if let Some(address) = self.dmc.fetch_address() {
+                let value = read(address);
+                self.dmc.fill(value);
+                // Usually 4 cycles, it depends on what the CPU was doing
+                remaining += DMC_DMA_CYCLES;
+                stall += DMC_DMA_CYCLES;
+            }
+
+            self.tick();
+            remaining -= 1;
+        }
+
+        stall
+    }
+
+    fn tick(&mut self) {
+        if self.cycle % 2 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+
+        self.clock_frame_sequencer();
+        self.cycle += 1;
+
+        if self.sink.is_some() || self.recorder.is_some() {
+            let sample = self.output();
+            if let Some(ref mut sink) = self.sink {
+                sink.sample(sample);
+            }
+            if self.recorder.is_some() {
+                let channels = self.channel_outputs();
+                if let Some(ref mut recorder) = self.recorder {
+                    recorder.record(sample, &channels);
+                }
+            }
+        }
+    }
+
+    // http://wiki.nesdev.com/w/index.php/APU_Frame_Counter
+    // NTSC timings, in CPU cycles
+    fn clock_frame_sequencer(&mut self) {
+        self.frame_cycle += 1;
+
+        match (self.frame_cycle, self.five_step_mode) {
+            (7457, _) | (22371, _) => self.clock_quarter_frame(),
+            (14913, _) | (29829, false) | (37281, true) => {
+                self.clock_quarter_frame();
+                self.clock_half_frame();
+            }
+            _ => ()
+        }
+
+        // Only the 4-step sequence raises an IRQ, on its last step
+        if self.frame_cycle == 29829 && !self.five_step_mode && !self.irq_inhibit {
+            self.frame_irq = true;
+        }
+
+        if (self.frame_cycle == 29830 && !self.five_step_mode) || self.frame_cycle == 37282 {
+            self.frame_cycle = 0;
+        }
+    }
+
+    // Envelopes and the triangle's linear counter
+    fn clock_quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.noise.envelope.clock();
+        self.triangle.clock_linear_counter();
+    }
+
+    // Length counters and sweep units
+    fn clock_half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // Between 0.0 and 1.0
+    pub fn output(&self) -> f32 {
+        mix(self.pulse1.output(), self.pulse2.output(), self.triangle.output(),
+            self.noise.output(), self.dmc.output())
+    }
+
+    // Each channel going through the mixer on its own
+    fn channel_outputs(&self) -> [f32; 5] {
+        [
+            mix(self.pulse1.output(), 0, 0, 0, 0),
+            mix(0, self.pulse2.output(), 0, 0, 0),
+            mix(0, 0, self.triangle.output(), 0, 0),
+            mix(0, 0, 0, self.noise.output(), 0),
+            mix(0, 0, 0, 0, self.dmc.output()),
+        ]
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        self.pulse1.save_state(writer)?;
+        self.pulse2.save_state(writer)?;
+        self.triangle.save_state(writer)?;
+        self.noise.save_state(writer)?;
+        self.dmc.save_state(writer)?;
+        write_u64(writer, self.cycle)?;
+        write_u32(writer, self.frame_cycle)?;
+        write_bool(writer, self.five_step_mode)?;
+        write_bool(writer, self.irq_inhibit)?;
+        write_bool(writer, self.frame_irq)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.pulse1.load_state(reader)?;
+        self.pulse2.load_state(reader)?;
+        self.triangle.load_state(reader)?;
+        self.noise.load_state(reader)?;
+        self.dmc.load_state(reader)?;
+        self.cycle = read_u64(reader)?;
+        self.frame_cycle = read_u32(reader)?;
+        self.five_step_mode = read_bool(reader)?;
+        self.irq_inhibit = read_bool(reader)?;
+        self.frame_irq = read_bool(reader)?;
+        Ok(())
+    }
+}
+
+impl Default for Apu {
+    fn default() -> Apu {
+        Apu::new()
+    }
+}
+
+// http://wiki.nesdev.com/w/index.php/APU_Mixer
+fn mix(pulse1: u8, pulse2: u8, triangle: u8, noise: u8, dmc: u8) -> f32 {
+    let pulse = (pulse1 + pulse2) as f32;
+    let pulse_out = if pulse == 0.0 {
+        0.0
+    } else {
+        95.88 / (8128.0 / pulse + 100.0)
+    };
+
+    let tnd = triangle as f32 / 8227.0 + noise as f32 / 12241.0 + dmc as f32 / 22638.0;
+    let tnd_out = if tnd == 0.0 {
+        0.0
+    } else {
+        159.79 / (1.0 / tnd + 100.0)
+    };
+
+    pulse_out + tnd_out
+}
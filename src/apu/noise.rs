@@ -0,0 +1,109 @@
+use std::io;
+use std::io::prelude::*;
+
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_Noise
+// NTSC timer periods, in CPU cycles
+const PERIOD_TABLE: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+pub struct Noise {
+    mode: bool, // Short mode uses bit 6 for the feedback instead of bit 1
+    shift: u16, // 15-bit linear feedback shift register
+    timer: u16,
+    timer_period: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new() -> Noise {
+        Noise {
+            mode: false,
+            shift: 1,
+            timer: 0,
+            timer_period: PERIOD_TABLE[0],
+
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+        }
+    }
+
+    // register is 0-3, for $400C-$400F ($400D is unused)
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // --LC VVVV
+            0 => {
+                self.length.set_halt(value & 0x20 != 0);
+                self.envelope.write_control(value);
+            }
+            1 => (),
+            // M--- PPPP
+            2 => {
+                self.mode = value & 0x80 != 0;
+                self.timer_period = PERIOD_TABLE[value as usize & 0x0F];
+            }
+            // LLLL L---
+            _ => {
+                self.length.load(value >> 3);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    // Clocked every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period - 1;
+
+            let tap = if self.mode { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.shift & 1 != 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_bool(writer, self.mode)?;
+        write_u16(writer, self.shift)?;
+        write_u16(writer, self.timer)?;
+        write_u16(writer, self.timer_period)?;
+        self.envelope.save_state(writer)?;
+        self.length.save_state(writer)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.mode = read_bool(reader)?;
+        self.shift = read_u16(reader)? & 0x7FFF;
+        self.timer = read_u16(reader)?;
+        let timer_period = read_u16(reader)?;
+        if !PERIOD_TABLE.contains(&timer_period) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData,
+                format!("invalid noise period {}", timer_period)));
+        }
+        self.timer_period = timer_period;
+        self.envelope.load_state(reader)?;
+        self.length.load_state(reader)
+    }
+}
+
+impl Default for Noise {
+    fn default() -> Noise {
+        Noise::new()
+    }
+}
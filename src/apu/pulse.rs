@@ -0,0 +1,168 @@
+use std::io;
+use std::io::prelude::*;
+
+use apu::envelope::Envelope;
+use apu::length_counter::LengthCounter;
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_Pulse
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0], // 12.5%
+    [0, 1, 1, 0, 0, 0, 0, 0], // 25%
+    [0, 1, 1, 1, 1, 0, 0, 0], // 50%
+    [1, 0, 0, 1, 1, 1, 1, 1], // 25% negated
+];
+
+pub struct Pulse {
+    // Pulse 1 negates its sweep with ones' complement, pulse 2 with two's complement
+    ones_complement: bool,
+
+    duty: u8,
+    sequence: u8,
+    timer: u16,
+    timer_period: u16,
+
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+
+    // http://wiki.nesdev.com/w/index.php/APU_Sweep
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Pulse {
+        Pulse {
+            ones_complement,
+
+            duty: 0,
+            sequence: 0,
+            timer: 0,
+            timer_period: 0,
+
+            envelope: Envelope::new(),
+            length: LengthCounter::new(),
+
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // register is 0-3, for $4000-$4003 or $4004-$4007
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // DDLC VVVV
+            0 => {
+                self.duty = value >> 6;
+                self.length.set_halt(value & 0x20 != 0);
+                self.envelope.write_control(value);
+            }
+            // EPPP NSSS
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period = (value >> 4) & 0x07;
+                self.sweep_negate = value & 0x08 != 0;
+                self.sweep_shift = value & 0x07;
+                self.sweep_reload = true;
+            }
+            // TTTT TTTT
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            // LLLL LTTT
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.envelope.restart();
+                self.sequence = 0;
+            }
+        }
+    }
+
+    // Clocked every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            self.sequence = (self.sequence + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.timer_period >> self.sweep_shift;
+        if self.sweep_negate {
+            let extra = if self.ones_complement { 1 } else { 0 };
+            self.timer_period.saturating_sub(change + extra)
+        } else {
+            self.timer_period + change
+        }
+    }
+
+    // The sweep unit mutes the channel even when it is disabled
+    fn muted(&self) -> bool {
+        self.timer_period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    // Half frame
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.timer_period = self.sweep_target();
+        }
+
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if !self.length.active() || self.muted() || DUTY_TABLE[self.duty as usize][self.sequence as usize] == 0 {
+            0
+        } else {
+            self.envelope.volume()
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, self.duty)?;
+        write_u8(writer, self.sequence)?;
+        write_u16(writer, self.timer)?;
+        write_u16(writer, self.timer_period)?;
+        self.envelope.save_state(writer)?;
+        self.length.save_state(writer)?;
+        write_bool(writer, self.sweep_enabled)?;
+        write_u8(writer, self.sweep_period)?;
+        write_bool(writer, self.sweep_negate)?;
+        write_u8(writer, self.sweep_shift)?;
+        write_u8(writer, self.sweep_divider)?;
+        write_bool(writer, self.sweep_reload)
+    }
+
+    // Values are masked to their register widths, so a corrupted state can't
+    // index past the duty table or overflow the sweep
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.duty = read_u8(reader)? & 0x03;
+        self.sequence = read_u8(reader)? & 0x07;
+        self.timer = read_u16(reader)? & 0x07FF;
+        self.timer_period = read_u16(reader)? & 0x07FF;
+        self.envelope.load_state(reader)?;
+        self.length.load_state(reader)?;
+        self.sweep_enabled = read_bool(reader)?;
+        self.sweep_period = read_u8(reader)? & 0x07;
+        self.sweep_negate = read_bool(reader)?;
+        self.sweep_shift = read_u8(reader)? & 0x07;
+        self.sweep_divider = read_u8(reader)? & 0x07;
+        self.sweep_reload = read_bool(reader)?;
+        Ok(())
+    }
+}
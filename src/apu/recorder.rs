@@ -0,0 +1,118 @@
+use std::fs::File;
+use std::io;
+use std::io::BufWriter;
+use std::path::{Path, PathBuf};
+
+use apu::AudioSink;
+use apu::CPU_CLOCK_RATE;
+use apu::resampler::Resampler;
+use apu::wav::WavWriter;
+
+// Suffixes of the per-channel files, e.g. out.wav -> out.pulse1.wav
+const CHANNEL_NAMES: [&str; 5] = ["pulse1", "pulse2", "triangle", "noise", "dmc"];
+
+// Resampled samples are written out every this many CPU cycles
+const FLUSH_CYCLES: u32 = 8192;
+
+// Records the APU output to WAV files, independently of any AudioSink
+pub struct Recorder {
+    mixed: Track,
+    channels: Vec<Track>,
+    cycles: u32,
+    error: Option<io::Error>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path, sample_rate: u32, per_channel: bool) -> io::Result<Recorder> {
+        let mut channels = Vec::new();
+        if per_channel {
+            for name in CHANNEL_NAMES.iter() {
+                channels.push(Track::create(&channel_path(path, name), sample_rate)?);
+            }
+        }
+
+        Ok(Recorder {
+            mixed: Track::create(path, sample_rate)?,
+            channels,
+            cycles: 0,
+            error: None,
+        })
+    }
+
+    // Called every CPU cycle with the mixed output and each channel on its own
+    pub fn record(&mut self, mixed: f32, channels: &[f32; 5]) {
+        self.mixed.resampler.sample(mixed);
+        for (track, sample) in self.channels.iter_mut().zip(channels.iter()) {
+            track.resampler.sample(*sample);
+        }
+
+        self.cycles += 1;
+        if self.cycles == FLUSH_CYCLES {
+            self.cycles = 0;
+            if let Err(e) = self.flush() {
+                // Reported when the recording stops
+                self.error.get_or_insert(e);
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.mixed.flush()?;
+        for track in self.channels.iter_mut() {
+            track.flush()?;
+        }
+        Ok(())
+    }
+
+    // Every file gets its sizes patched, even after an error, so whatever was
+    // recorded stays playable. The first error is the one returned.
+    pub fn finish(mut self) -> io::Result<()> {
+        let mut result = match self.error.take() {
+            Some(e) => Err(e),
+            None => Ok(()),
+        };
+
+        result = result.and(self.mixed.finish());
+        for track in self.channels {
+            result = result.and(track.finish());
+        }
+        result
+    }
+}
+
+struct Track {
+    resampler: Resampler,
+    writer: WavWriter<BufWriter<File>>,
+    samples: Vec<f32>,
+}
+
+impl Track {
+    fn create(path: &Path, sample_rate: u32) -> io::Result<Track> {
+        let file = File::create(path)?;
+        Ok(Track {
+            resampler: Resampler::new(CPU_CLOCK_RATE, sample_rate),
+            writer: WavWriter::new(BufWriter::new(file), sample_rate)?,
+            samples: Vec::new(),
+        })
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.samples.clear();
+        self.resampler.read_samples(&mut self.samples);
+        for sample in self.samples.iter() {
+            self.writer.write_sample(*sample)?;
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        let flushed = self.flush();
+        let finished = self.writer.finish();
+        flushed.and(finished.map(|_| ()))
+    }
+}
+
+fn channel_path(path: &Path, channel: &str) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    path.with_file_name(format!("{}.{}.wav", stem, channel))
+}
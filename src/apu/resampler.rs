@@ -0,0 +1,176 @@
+use std::f64::consts::PI;
+
+use apu::AudioSink;
+
+// Band-limited resampling of the APU output, in the spirit of blip_buf: every
+// change in amplitude is added to the output buffer as a band-limited step, so
+// the ~1.79MHz stream can be brought down to 44.1/48kHz without aliasing.
+// http://www.slack.net/~ant/bl-synth/
+
+const TAPS: usize = 16; // Kernel width, in output samples
+const PHASES: usize = 64; // Sub-sample resolution of the kernel
+const CUTOFF: f64 = 0.9; // Fraction of the output Nyquist frequency
+
+pub struct Resampler {
+    clock_rate: f64,
+    sample_rate: f64,
+    ratio: f64, // Output samples per input sample
+
+    kernel: Vec<[f32; TAPS]>,
+    buffer: Vec<f32>, // Band-limited impulses, integrated when read back
+    position: f64, // Where the next input sample lands in `buffer`
+    last: f32,
+    integrator: f64,
+
+    filters: [Filter; 3],
+}
+
+impl Resampler {
+    pub fn new(clock_rate: f64, sample_rate: u32) -> Resampler {
+        let sample_rate = sample_rate as f64;
+
+        // http://wiki.nesdev.com/w/index.php/APU_Mixer
+        // The console's output goes through two high-pass filters and a low-pass one
+        let filters = [
+            Filter::high_pass(90.0, sample_rate),
+            Filter::high_pass(440.0, sample_rate),
+            Filter::low_pass(14000.0, sample_rate),
+        ];
+
+        Resampler {
+            clock_rate,
+            sample_rate,
+            ratio: sample_rate / clock_rate,
+
+            kernel: make_kernel(),
+            buffer: Vec::new(),
+            position: (TAPS / 2) as f64,
+            last: 0.0,
+            integrator: 0.0,
+
+            filters,
+        }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate as u32
+    }
+
+    // Stretches or squeezes the output slightly (e.g. 1.005 yields 0.5% more samples),
+    // so the frontend can keep its audio buffer from draining or piling up.
+    pub fn set_rate_adjustment(&mut self, adjustment: f64) {
+        self.ratio = self.sample_rate * adjustment / self.clock_rate;
+    }
+
+    fn add_delta(&mut self, delta: f32) {
+        let whole = self.position.floor();
+        let phase = ((self.position - whole) * PHASES as f64) as usize;
+        let start = whole as usize + 1 - TAPS / 2;
+
+        if self.buffer.len() < start + TAPS {
+            self.buffer.resize(start + TAPS, 0.0);
+        }
+
+        for (i, k) in self.kernel[phase].iter().enumerate() {
+            self.buffer[start + i] += delta * k;
+        }
+    }
+
+    // Number of output samples that no future input can change anymore
+    pub fn samples_available(&self) -> usize {
+        self.position as usize + 1 - TAPS / 2
+    }
+
+    // Moves every finished sample into `out`
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let count = self.samples_available();
+        if self.buffer.len() < count {
+            self.buffer.resize(count, 0.0);
+        }
+
+        for impulse in self.buffer.drain(..count) {
+            self.integrator += impulse as f64;
+            let mut sample = self.integrator as f32;
+            for filter in self.filters.iter_mut() {
+                sample = filter.apply(sample);
+            }
+            out.push(sample);
+        }
+
+        self.position -= count as f64;
+    }
+}
+
+impl AudioSink for Resampler {
+    fn sample(&mut self, sample: f32) {
+        if sample != self.last {
+            self.add_delta(sample - self.last);
+            self.last = sample;
+        }
+        self.position += self.ratio;
+    }
+}
+
+// Windowed sinc impulses, one per phase. Each sums to 1 so steps keep their height.
+fn make_kernel() -> Vec<[f32; TAPS]> {
+    let half = (TAPS / 2) as f64;
+
+    (0..PHASES).map(|phase| {
+        let offset = phase as f64 / PHASES as f64;
+        let mut taps = [0.0; TAPS];
+        let mut sum = 0.0;
+
+        for (i, tap) in taps.iter_mut().enumerate() {
+            // Distance from the impulse, in output samples
+            let t = i as f64 - (half - 1.0) - offset;
+            let x = PI * CUTOFF * t;
+            let sinc = if x == 0.0 { 1.0 } else { x.sin() / x };
+            // Blackman window
+            let w = 0.42 + 0.5 * (PI * t / half).cos() + 0.08 * (2.0 * PI * t / half).cos();
+            *tap = sinc * w;
+            sum += *tap;
+        }
+
+        let mut kernel = [0.0; TAPS];
+        for (k, tap) in kernel.iter_mut().zip(taps.iter()) {
+            *k = (tap / sum) as f32;
+        }
+        kernel
+    }).collect()
+}
+
+// First order IIR filter
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    previous_input: f32,
+    previous_output: f32,
+}
+
+impl Filter {
+    fn high_pass(frequency: f64, sample_rate: f64) -> Filter {
+        let rc = 1.0 / (2.0 * PI * frequency);
+        Filter::new(true, (rc / (rc + 1.0 / sample_rate)) as f32)
+    }
+
+    fn low_pass(frequency: f64, sample_rate: f64) -> Filter {
+        let rc = 1.0 / (2.0 * PI * frequency);
+        let dt = 1.0 / sample_rate;
+        Filter::new(false, (dt / (rc + dt)) as f32)
+    }
+
+    fn new(high_pass: bool, alpha: f32) -> Filter {
+        Filter { high_pass, alpha, previous_input: 0.0, previous_output: 0.0 }
+    }
+
+    fn apply(&mut self, input: f32) -> f32 {
+        let output = if self.high_pass {
+            self.alpha * (self.previous_output + input - self.previous_input)
+        } else {
+            self.previous_output + self.alpha * (input - self.previous_output)
+        };
+        self.previous_input = input;
+        self.previous_output = output;
+        output
+    }
+}
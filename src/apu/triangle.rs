@@ -0,0 +1,122 @@
+use std::io;
+use std::io::prelude::*;
+
+use apu::length_counter::LengthCounter;
+use savestate::*;
+
+// http://wiki.nesdev.com/w/index.php/APU_Triangle
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+pub struct Triangle {
+    sequence: u8,
+    timer: u16,
+    timer_period: u16,
+
+    pub length: LengthCounter,
+
+    control: bool, // Also halts the length counter
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    pub fn new() -> Triangle {
+        Triangle {
+            sequence: 0,
+            timer: 0,
+            timer_period: 0,
+
+            length: LengthCounter::new(),
+
+            control: false,
+            linear_reload_value: 0,
+            linear_counter: 0,
+            linear_reload: false,
+        }
+    }
+
+    // register is 0-3, for $4008-$400B ($4009 is unused)
+    pub fn write(&mut self, register: u16, value: u8) {
+        match register {
+            // CRRR RRRR
+            0 => {
+                self.control = value & 0x80 != 0;
+                self.length.set_halt(self.control);
+                self.linear_reload_value = value & 0x7F;
+            }
+            1 => (),
+            // TTTT TTTT
+            2 => self.timer_period = (self.timer_period & 0x0700) | value as u16,
+            // LLLL LTTT
+            _ => {
+                self.timer_period = (self.timer_period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // Clocked every CPU cycle, the sequencer only moves while both counters are non zero
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.timer_period;
+            if self.length.active() && self.linear_counter > 0 {
+                self.sequence = (self.sequence + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // Quarter frame
+    pub fn clock_linear_counter(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // 0-15. Silencing the channel just freezes the sequencer, it keeps outputting its last value.
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.sequence as usize]
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, self.sequence)?;
+        write_u16(writer, self.timer)?;
+        write_u16(writer, self.timer_period)?;
+        self.length.save_state(writer)?;
+        write_bool(writer, self.control)?;
+        write_u8(writer, self.linear_reload_value)?;
+        write_u8(writer, self.linear_counter)?;
+        write_bool(writer, self.linear_reload)
+    }
+
+    // Masked like Pulse::load_state, the sequence indexes SEQUENCE
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.sequence = read_u8(reader)? & 0x1F;
+        self.timer = read_u16(reader)? & 0x07FF;
+        self.timer_period = read_u16(reader)? & 0x07FF;
+        self.length.load_state(reader)?;
+        self.control = read_bool(reader)?;
+        self.linear_reload_value = read_u8(reader)? & 0x7F;
+        self.linear_counter = read_u8(reader)? & 0x7F;
+        self.linear_reload = read_bool(reader)?;
+        Ok(())
+    }
+}
+
+impl Default for Triangle {
+    fn default() -> Triangle {
+        Triangle::new()
+    }
+}
@@ -2,31 +2,95 @@ extern crate sen;
 extern crate sdl2;
 extern crate time;
 
+use std::cell::RefCell;
 use std::env;
+use std::error::Error;
 use std::fs::File;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
 
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
 
+use sen::apu::CPU_CLOCK_RATE;
+use sen::apu::resampler::Resampler;
 use sen::cpu::Cpu;
-use sen::ppu::Ppu;
+use sen::ppu::{Ppu, SCREEN_HEIGHT, SCREEN_WIDTH};
 use sen::cartridge::Cartridge;
 use sen::controller::Controller;
 use sen::memory::CpuMemory;
+use sen::ntsc::{NtscFilter, NtscPreset, OUTPUT_WIDTH};
+use sen::palette;
+use sen::savestate;
+use sen::scale::{self, Image, ScaleFilter};
+
+// Roughly every 5 seconds
+const SAVE_RAM_FLUSH_FRAMES: u32 = 300;
+
+// How much audio we try to keep queued, in seconds
+const AUDIO_LATENCY: f64 = 0.05;
+// Largest resampling ratio change used to keep the queue at that level
+const MAX_RATE_ADJUSTMENT: f64 = 0.005;
 
 fn main() {
     let args: Vec<String> = env::args().collect();
-    let path = Path::new(&args[1]);
+    let options = match parse_args(&args[1..]) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("{}", e);
+            eprintln!("Usage: {} [--palette <file.pal|ntsc>] [--ntsc <composite|svideo|rgb>] [--scale <1-8>] [--filter <nearest|scale2x|scale3x|hq2x|scanlines>] [--square-pixels] [--record-audio <out.wav>] [--record-channels] <rom.nes>", args[0]);
+            process::exit(1);
+        }
+    };
+    let path = options.rom.as_path();
 
-    let mut file = File::open(path).unwrap();
-    let cartridge = Cartridge::load(&mut file);
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) => {
+            eprintln!("Couldn't open {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
+
+    let cartridge = match Cartridge::load(&mut file) {
+        Ok(cartridge) => cartridge,
+        Err(e) => {
+            eprintln!("Couldn't load {}: {}", path.display(), e);
+            process::exit(1);
+        }
+    };
 
     print!("Loaded ROM at {:?}", path);
     println!(" - {}", cartridge.header);
 
-    let ppu = Ppu::new(cartridge.clone());
+    let save_path = path.with_extension("sav");
+    let battery = cartridge.header.battery();
+    if battery {
+        if let Ok(mut file) = File::open(&save_path) {
+            match cartridge.read_save_ram(&mut file) {
+                Ok(_) => println!("Loaded save RAM from {:?}", save_path),
+                Err(e) => eprintln!("Couldn't read {}: {}", save_path.display(), e),
+            }
+        }
+    }
+    let mut saved_ram = cartridge.save_ram();
+    let mut frames_since_save = 0;
+
+    // 0-9 select a slot, F5 saves and F7 loads
+    let mut state_slot = 0;
+
+    let mut ppu = Ppu::new(cartridge.clone());
+    if let Some(ref source) = options.palette {
+        match load_palette(source) {
+            Ok(colors) => ppu.set_palette(colors),
+            Err(e) => eprintln!("Couldn't load palette {}: {}", source, e),
+        }
+    }
     let controller = Controller::new();
     let memory = CpuMemory::new(cartridge, ppu, controller);
     let mut cpu = Cpu::new(memory);
@@ -34,10 +98,39 @@ fn main() {
     cpu.reset();
     cpu.ram.ppu.reset();
 
+    if let Some(ref wav_path) = options.record_audio {
+        match cpu.ram.apu.start_recording(wav_path, options.record_channels) {
+            Ok(_) => println!("Recording audio to {:?}", wav_path),
+            Err(e) => eprintln!("Couldn't record audio to {}: {}", wav_path.display(), e),
+        }
+    }
+
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
-    let window = video_subsystem.window("wat", 256, 240)
+    // The NTSC filter doubles the horizontal resolution, then the scaler enlarges
+    // the picture by whole factors and it gets resized to the window, all on the
+    // CPU so SDL only has to copy it
+    let mut ntsc = options.ntsc.map(NtscFilter::new);
+    if let Some(ref mut filter) = ntsc {
+        // Only the RGB preset has a use for --palette, see parse_args
+        if options.palette.is_some() {
+            filter.set_palette(cpu.ram.ppu.palette().to_vec());
+        }
+    }
+    let frame_width = if ntsc.is_some() { OUTPUT_WIDTH } else { SCREEN_WIDTH };
+    let factor = options.filter.factor(options.scale);
+    let (scaled_width, scaled_height) = (frame_width * factor, SCREEN_HEIGHT * factor);
+
+    let window_width = if options.square_pixels {
+        SCREEN_WIDTH * options.scale
+    } else {
+        scale::aspect_width(options.scale)
+    };
+    let window_height = SCREEN_HEIGHT * options.scale;
+    let resized = (scaled_width, scaled_height) != (window_width, window_height);
+
+    let window = video_subsystem.window("wat", window_width as u32, window_height as u32)
         .position_centered()
         .opengl()
         .build()
@@ -47,20 +140,32 @@ fn main() {
 
     canvas.clear();
 
+    // Emulation is paced by the audio queue when there is one
+    let audio = open_audio(&sdl_context);
+    let mut samples = Vec::new();
+    if let Some((ref queue, ref resampler)) = audio {
+        cpu.ram.apu.set_sink(Some(Box::new(resampler.clone())));
+        queue.resume();
+    }
+
     let mut event_pump = sdl_context.event_pump().unwrap();
     let tex_creator = canvas.texture_creator();
-    let mut texture = tex_creator.create_texture_target(PixelFormatEnum::BGR24, 256, 240).unwrap();
+    let mut texture = tex_creator
+        .create_texture_target(PixelFormatEnum::RGB24, window_width as u32, window_height as u32)
+        .unwrap();
 
+    let mut ntsc_pixels = vec![0; OUTPUT_WIDTH * SCREEN_HEIGHT * 3];
+    let mut pixels = Vec::new();
     let mut previous_time = time::precise_time_s();
 
     'running: loop {
+        // The PPU and APU are clocked by the CPU as it goes
         cpu.step();
-        let ppu_result = cpu.ram.ppu.step(cpu.cycle);
         cpu.cycle = 0;
 
-        if ppu_result.nmi { cpu.nmi(); }
+        if cpu.ram.ppu.new_frame {
+            cpu.ram.ppu.new_frame = false;
 
-        if ppu_result.new_frame {
             let t = time::precise_time_s();
             if t > previous_time + 1 as f64 {
                 println!("{} FPS", cpu.ram.ppu.frames);
@@ -68,7 +173,31 @@ fn main() {
                 cpu.ram.ppu.frames = 0;
             }
 
-            texture.update(None, &cpu.ram.ppu.frame_content, 256 * 3).unwrap();
+            frames_since_save += 1;
+            if battery && frames_since_save >= SAVE_RAM_FLUSH_FRAMES {
+                flush_save_ram(&cpu.ram.cartridge, &save_path, &mut saved_ram);
+                frames_since_save = 0;
+            }
+
+            if let Some((ref queue, ref resampler)) = audio {
+                play_audio(queue, &mut resampler.borrow_mut(), &mut samples);
+            }
+
+            let ppu = &cpu.ram.ppu;
+            let image = match ntsc {
+                Some(ref mut filter) => {
+                    filter.filter(ppu.frame(), &mut ntsc_pixels);
+                    Image::from_rgb24(&ntsc_pixels, OUTPUT_WIDTH, SCREEN_HEIGHT)
+                }
+                None => Image::from_indexed(ppu.frame(), ppu.palette(), SCREEN_WIDTH, SCREEN_HEIGHT),
+            };
+            let scaled = options.filter.apply(&image, options.scale);
+            if resized {
+                scale::resize(&scaled, window_width, window_height).to_rgb24(&mut pixels);
+            } else {
+                scaled.to_rgb24(&mut pixels);
+            }
+            texture.update(None, &pixels, window_width * 3).unwrap();
             canvas.clear();
             canvas.copy(&texture, None, None).unwrap();
             canvas.present();
@@ -87,9 +216,207 @@ fn main() {
                     Event::Quit {..} | Event::KeyDown { keycode: Some(Keycode::Escape), .. } => {
                         break 'running
                     },
+                    Event::KeyDown { keycode: Some(Keycode::F5), .. } => {
+                        save_state(&cpu, &state_path(path, state_slot));
+                    },
+                    Event::KeyDown { keycode: Some(Keycode::F7), .. } => {
+                        load_state(&mut cpu, &state_path(path, state_slot));
+                    },
+                    Event::KeyDown { keycode: Some(key), .. } => {
+                        if let Some(slot) = slot_for_key(key) {
+                            println!("Selected save state slot {}", slot);
+                            state_slot = slot;
+                        }
+                    },
                     _ => ()
                 }
             }
         }
     }
+
+    if battery {
+        flush_save_ram(&cpu.ram.cartridge, &save_path, &mut saved_ram);
+    }
+
+    if let Err(e) = cpu.ram.apu.stop_recording() {
+        eprintln!("Couldn't finish the audio recording: {}", e);
+    }
+}
+
+struct Options {
+    rom: PathBuf,
+    palette: Option<String>, // A .pal file, or "ntsc" to generate one
+    ntsc: Option<NtscPreset>,
+    scale: usize, // Of the window
+    filter: ScaleFilter,
+    square_pixels: bool, // Don't stretch to the 8:7 pixel aspect ratio
+    record_audio: Option<PathBuf>,
+    record_channels: bool, // Also write each APU channel to its own file
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut rom = None;
+    let mut palette = None;
+    let mut ntsc = None;
+    let mut scale = 2;
+    let mut filter = ScaleFilter::Nearest;
+    let mut square_pixels = false;
+    let mut record_audio = None;
+    let mut record_channels = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--palette" => match args.next() {
+                Some(source) => palette = Some(source.clone()),
+                None => return Err("--palette needs a file name".to_string()),
+            },
+            "--ntsc" => match args.next().map(|name| NtscPreset::parse(name)) {
+                Some(Some(preset)) => ntsc = Some(preset),
+                _ => return Err("--ntsc needs composite, svideo or rgb".to_string()),
+            },
+            "--scale" => match args.next().and_then(|n| n.parse().ok()) {
+                Some(n @ 1..=8) => scale = n,
+                _ => return Err("--scale needs a number between 1 and 8".to_string()),
+            },
+            "--filter" => match args.next().map(|name| ScaleFilter::parse(name)) {
+                Some(Some(f)) => filter = f,
+                _ => return Err("--filter needs nearest, scale2x, scale3x, hq2x or scanlines".to_string()),
+            },
+            "--square-pixels" => square_pixels = true,
+            "--record-audio" => match args.next() {
+                Some(path) => record_audio = Some(PathBuf::from(path)),
+                None => return Err("--record-audio needs a file name".to_string()),
+            },
+            "--record-channels" => record_channels = true,
+            _ if arg.starts_with("--") => return Err(format!("Unknown option {}", arg)),
+            _ => rom = Some(PathBuf::from(arg)),
+        }
+    }
+
+    // The composite and S-Video colors come out of the simulated signal
+    if palette.is_some() && ntsc.is_some_and(|preset| preset != NtscPreset::Rgb) {
+        return Err("--palette only works with --ntsc rgb".to_string());
+    }
+
+    if record_channels && record_audio.is_none() {
+        return Err("--record-channels needs --record-audio".to_string());
+    }
+
+    match rom {
+        Some(rom) => Ok(Options {
+            rom,
+            palette,
+            ntsc,
+            scale,
+            filter,
+            square_pixels,
+            record_audio,
+            record_channels,
+        }),
+        None => Err("No ROM given".to_string()),
+    }
+}
+
+fn load_palette(source: &str) -> Result<Vec<u32>, Box<dyn Error>> {
+    if source == "ntsc" {
+        return Ok(palette::generate(&palette::NtscSettings::new()));
+    }
+
+    let mut file = File::open(source)?;
+    Ok(palette::load(&mut file)?)
+}
+
+fn open_audio(sdl_context: &sdl2::Sdl) -> Option<(AudioQueue<f32>, Rc<RefCell<Resampler>>)> {
+    let desired = AudioSpecDesired { freq: Some(48000), channels: Some(1), samples: Some(1024) };
+    let queue = sdl_context.audio()
+        .and_then(|audio| audio.open_queue::<f32, _>(None, &desired));
+
+    match queue {
+        Ok(queue) => {
+            let resampler = Resampler::new(CPU_CLOCK_RATE, queue.spec().freq as u32);
+            Some((queue, Rc::new(RefCell::new(resampler))))
+        }
+        Err(e) => {
+            eprintln!("Couldn't open audio device, running without sound: {}", e);
+            None
+        }
+    }
+}
+
+// Queues the samples of the last frame, then waits for the queue to drain down to
+// AUDIO_LATENCY, which keeps emulation at the right speed. Dynamic rate control
+// nudges the resampling ratio so the queue hovers around that level instead of
+// underflowing or drifting.
+// https://github.com/higan-emu/emulation-articles/tree/master/audio/dynamic-rate-control
+fn play_audio(queue: &AudioQueue<f32>, resampler: &mut Resampler, samples: &mut Vec<f32>) {
+    let bytes_per_second = resampler.sample_rate() as f64 * 4.0;
+
+    let queued = queue.size() as f64 / bytes_per_second;
+    let error = ((AUDIO_LATENCY - queued) / AUDIO_LATENCY).clamp(-1.0, 1.0);
+    resampler.set_rate_adjustment(1.0 + error * MAX_RATE_ADJUSTMENT);
+
+    samples.clear();
+    resampler.read_samples(samples);
+    queue.queue(samples);
+
+    let target = (AUDIO_LATENCY * bytes_per_second) as u32;
+    while queue.size() > target {
+        thread::sleep(Duration::from_millis(1));
+    }
+}
+
+// Writes the battery backed RAM to disk, unless it didn't change since last time
+fn flush_save_ram(cartridge: &Cartridge, path: &Path, saved_ram: &mut Vec<u8>) {
+    let ram = cartridge.save_ram();
+    if ram == *saved_ram {
+        return;
+    }
+
+    let result = File::create(path).and_then(|mut file| cartridge.write_save_ram(&mut file));
+    match result {
+        Ok(_) => *saved_ram = ram,
+        Err(e) => eprintln!("Couldn't write {}: {}", path.display(), e),
+    }
+}
+
+fn slot_for_key(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::Num0 => Some(0),
+        Keycode::Num1 => Some(1),
+        Keycode::Num2 => Some(2),
+        Keycode::Num3 => Some(3),
+        Keycode::Num4 => Some(4),
+        Keycode::Num5 => Some(5),
+        Keycode::Num6 => Some(6),
+        Keycode::Num7 => Some(7),
+        Keycode::Num8 => Some(8),
+        Keycode::Num9 => Some(9),
+        _ => None,
+    }
+}
+
+// e.g. zelda.nes -> zelda.ss3
+fn state_path(rom: &Path, slot: u8) -> PathBuf {
+    rom.with_extension(format!("ss{}", slot))
+}
+
+fn save_state(cpu: &Cpu, path: &Path) {
+    let result = File::create(path)
+        .map_err(savestate::SaveStateError::from)
+        .and_then(|mut file| savestate::save(cpu, &mut file));
+    match result {
+        Ok(_) => println!("Saved state to {:?}", path),
+        Err(e) => eprintln!("Couldn't save state to {}: {}", path.display(), e),
+    }
+}
+
+fn load_state(cpu: &mut Cpu, path: &Path) {
+    let result = File::open(path)
+        .map_err(savestate::SaveStateError::from)
+        .and_then(|mut file| savestate::load(cpu, &mut file));
+    match result {
+        Ok(_) => println!("Loaded state from {:?}", path),
+        Err(e) => eprintln!("Couldn't load state from {}: {}", path.display(), e),
+    }
 }
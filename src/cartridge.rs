@@ -1,9 +1,15 @@
 use std;
-use std::fs::File;
+use std::cell::RefCell;
+use std::error::Error;
+use std::io;
 use std::io::prelude::*;
 use std::fmt;
+use std::rc::Rc;
+
+use mapper::{Axrom, Cnrom, Gxrom, Mapper, Mmc1, Mmc3, Nrom, Uxrom};
 
 // Reference: http://wiki.nesdev.com/w/index.php/INES
+// NES 2.0: http://wiki.nesdev.com/w/index.php/NES_2.0
 
 #[allow(dead_code)]
 #[derive(Clone)]
@@ -13,61 +19,392 @@ pub struct NesHeader {
     chr_size: u8,   // CHR Rom banks (by increments of 8KB)
     flags_6: u8,
     flags_7: u8,
-    ram_size: u8,   // PRG Ram size (by increments of 8KB)
-    //unused: [u8; 7] // Unused stuff
+    ram_size: u8,   // PRG Ram size (by increments of 8KB), mapper MSB/submapper in NES 2.0
+    flags_9: u8,
+    flags_10: u8,
+    flags_11: u8,
+    flags_12: u8,
+    flags_13: u8,
+    flags_14: u8,
+    flags_15: u8,
+
+    // Everything below is decoded from the raw bytes above,
+    // using iNES 1.0 semantics when the NES 2.0 identifier is absent.
+    pub nes2: bool,
+    pub mapper: u16,
+    pub submapper: u8,
+    pub prg_rom_size: usize, // All sizes are in bytes
+    pub chr_rom_size: usize,
+    pub prg_ram_size: usize,
+    pub prg_nvram_size: usize,
+    pub chr_ram_size: usize,
+    pub chr_nvram_size: usize,
+    pub timing: Timing,
+    pub console: ConsoleType,
+    pub expansion_device: u8,
+}
+
+// http://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    SingleScreenLower,
+    SingleScreenUpper,
+    FourScreen,
+}
+
+// CPU/PPU timing (byte 12)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Timing {
+    Ntsc,
+    Pal,
+    MultiRegion,
+    Dendy,
 }
 
+// Console type (flags 7 bits 0-1, byte 13 for extended types)
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConsoleType {
+    Nes,
+    VsSystem,
+    Playchoice10,
+    Extended(u8),
+}
+
+// NES 2.0 RAM sizes are stored as shift counts: 64 << n bytes, 0 meaning none
+fn ram_size_from_shift(shift: u8) -> usize {
+    if shift == 0 { 0 } else { 64 << shift as usize }
+}
+
+// Nothing ever came close, anything bigger is a broken header
+const MAX_ROM_SIZE: usize = 64 * 1024 * 1024;
+
+// NES 2.0 ROM sizes. An MSB nybble of $F switches to exponent-multiplier
+// notation (2^E * (MM * 2 + 1) bytes), otherwise it's a plain bank count.
+// Returns None for sizes that don't fit or are over MAX_ROM_SIZE.
+fn rom_size(lsb: u8, msb: u8, bank_size: usize) -> Option<usize> {
+    let size = if msb == 0xF {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0x3) as usize * 2 + 1;
+        1usize.checked_shl(exponent)?.checked_mul(multiplier)?
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * bank_size
+    };
+
+    if size > MAX_ROM_SIZE { None } else { Some(size) }
+}
+
+impl NesHeader {
+    pub fn parse(bytes: &[u8; 16]) -> Result<NesHeader, CartridgeError> {
+        let mut header = NesHeader {
+            magic: [bytes[0], bytes[1], bytes[2], bytes[3]],
+            prg_size: bytes[4],
+            chr_size: bytes[5],
+            flags_6: bytes[6],
+            flags_7: bytes[7],
+            ram_size: bytes[8],
+            flags_9: bytes[9],
+            flags_10: bytes[10],
+            flags_11: bytes[11],
+            flags_12: bytes[12],
+            flags_13: bytes[13],
+            flags_14: bytes[14],
+            flags_15: bytes[15],
+
+            nes2: bytes[7] & 0x0C == 0x08,
+            mapper: 0,
+            submapper: 0,
+            prg_rom_size: 0,
+            chr_rom_size: 0,
+            prg_ram_size: 0,
+            prg_nvram_size: 0,
+            chr_ram_size: 0,
+            chr_nvram_size: 0,
+            timing: Timing::Ntsc,
+            console: ConsoleType::Nes,
+            expansion_device: 0,
+        };
+
+        if header.nes2 {
+            header.parse_nes2()?;
+        } else {
+            header.parse_ines();
+        }
+
+        Ok(header)
+    }
+
+    fn parse_nes2(&mut self) -> Result<(), CartridgeError> {
+        self.mapper = (self.ram_size as u16 & 0x0F) << 8
+            | (self.flags_7 & 0xF0) as u16
+            | (self.flags_6 >> 4) as u16;
+        self.submapper = self.ram_size >> 4;
+
+        self.prg_rom_size = rom_size(self.prg_size, self.flags_9 & 0x0F, 0x4000)
+            .ok_or(CartridgeError::InvalidHeader("PRG ROM size is too large"))?;
+        self.chr_rom_size = rom_size(self.chr_size, self.flags_9 >> 4, 0x2000)
+            .ok_or(CartridgeError::InvalidHeader("CHR ROM size is too large"))?;
+
+        self.prg_ram_size = ram_size_from_shift(self.flags_10 & 0x0F);
+        self.prg_nvram_size = ram_size_from_shift(self.flags_10 >> 4);
+        self.chr_ram_size = ram_size_from_shift(self.flags_11 & 0x0F);
+        self.chr_nvram_size = ram_size_from_shift(self.flags_11 >> 4);
+
+        self.timing = match self.flags_12 & 0x03 {
+            0 => Timing::Ntsc,
+            1 => Timing::Pal,
+            2 => Timing::MultiRegion,
+            _ => Timing::Dendy,
+        };
+
+        self.console = match self.flags_7 & 0x03 {
+            0 => ConsoleType::Nes,
+            1 => ConsoleType::VsSystem,
+            2 => ConsoleType::Playchoice10,
+            _ => ConsoleType::Extended(self.flags_13 & 0x0F),
+        };
+
+        self.expansion_device = self.flags_15 & 0x3F;
+        Ok(())
+    }
+
+    fn parse_ines(&mut self) {
+        // Old dumping tools wrote garbage (e.g. "DiskDude!") in bytes 7-15,
+        // in which case the upper nybble of the mapper can't be trusted.
+        let garbage = self.flags_12 != 0 || self.flags_13 != 0 || self.flags_14 != 0 || self.flags_15 != 0;
+        let high = if garbage { 0 } else { self.flags_7 & 0xF0 };
+        self.mapper = (high | (self.flags_6 >> 4)) as u16;
+
+        self.prg_rom_size = self.prg_size as usize * 0x4000;
+        self.chr_rom_size = self.chr_size as usize * 0x2000;
+
+        // A value of 0 infers 8KB for compatibility
+        let banks = if garbage || self.ram_size == 0 { 1 } else { self.ram_size as usize };
+        let ram = banks * 0x2000;
+        if self.battery() {
+            self.prg_nvram_size = ram;
+        } else {
+            self.prg_ram_size = ram;
+        }
+
+        if self.chr_size == 0 {
+            self.chr_ram_size = 0x2000;
+        }
+
+        if !garbage && self.flags_9 & 0x01 != 0 {
+            self.timing = Timing::Pal;
+        }
+
+        if !garbage {
+            self.console = match self.flags_7 & 0x03 {
+                0 => ConsoleType::Nes,
+                1 => ConsoleType::VsSystem,
+                _ => ConsoleType::Playchoice10,
+            };
+        }
+    }
+
+    pub fn mapper(&self) -> u16 {
+        self.mapper
+    }
+
+    // 512 byte trainer between the header and PRG ROM
+    pub fn trainer(&self) -> bool {
+        self.flags_6 & 0x04 != 0
+    }
+
+    // Battery backed PRG RAM at $6000-$7FFF
+    pub fn battery(&self) -> bool {
+        self.flags_6 & 0x02 != 0
+    }
+
+    // Bit 3 (four-screen VRAM on the cartridge) takes precedence over bit 0
+    pub fn mirroring(&self) -> Mirroring {
+        if self.flags_6 & 0x08 != 0 {
+            Mirroring::FourScreen
+        } else if self.flags_6 & 0x01 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        }
+    }
+}
+
+// Cloning a cartridge is cheap: the clones share the same mapper, which is
+// how both the CPU and the PPU get to see the same bank switching state.
 #[derive(Clone)]
 pub struct Cartridge {
     pub header: NesHeader,
-    pub prg: Vec<u8>,
-    pub chr: Vec<u8>,
-    pub ram: Vec<u8>,
+    pub mapper: Rc<RefCell<Box<dyn Mapper>>>,
+}
+
+#[derive(Debug)]
+pub enum CartridgeError {
+    BadMagic([u8; 4]),
+    TruncatedHeader,
+    InvalidHeader(&'static str),
+    TruncatedTrainer,
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    UnsupportedMapper(u16),
+    Io(io::Error),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            CartridgeError::BadMagic(magic) => write!(f, "not an iNES file (magic number is {:02x?})", magic),
+            CartridgeError::TruncatedHeader => write!(f, "the header is truncated"),
+            CartridgeError::InvalidHeader(reason) => write!(f, "invalid header: {}", reason),
+            CartridgeError::TruncatedTrainer => write!(f, "the trainer is truncated"),
+            CartridgeError::TruncatedPrg { expected, actual } =>
+                write!(f, "PRG ROM is truncated (expected {} bytes, got {})", expected, actual),
+            CartridgeError::TruncatedChr { expected, actual } =>
+                write!(f, "CHR ROM is truncated (expected {} bytes, got {})", expected, actual),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "mapper {} is not supported", n),
+            CartridgeError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
 }
 
-// TODO: Handle EOF errors
-fn file_to_buffer(buffer: &mut [u8], file: &mut File) {
+impl Error for CartridgeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            CartridgeError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> CartridgeError {
+        CartridgeError::Io(e)
+    }
+}
+
+// Reads until the buffer is full or EOF is reached.
+// Returns how many bytes were actually read.
+fn read_to_buffer<R: Read>(buffer: &mut [u8], reader: &mut R) -> io::Result<usize> {
     let mut i = 0;
     while i < buffer.len() {
-        let n = file.read(&mut buffer[i..]).unwrap();
-        i += n
+        match reader.read(&mut buffer[i..]) {
+            Ok(0) => break,
+            Ok(n) => i += n,
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(i)
+}
+
+// Reads up to `size` bytes. The buffer grows with the data actually read, so a
+// header claiming a huge ROM can't make us allocate it upfront.
+fn read_section<R: Read>(reader: &mut R, size: usize) -> io::Result<Vec<u8>> {
+    let mut data = Vec::new();
+    reader.by_ref().take(size as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+// The boards with a fixed last bank need at least one whole bank of PRG ROM.
+// MMC3 has 8KB banks, but fixes the last two.
+fn min_prg_rom_size(mapper: u16) -> usize {
+    match mapper {
+        1 | 2 | 4 => 0x4000,
+        _ => 1,
     }
 }
 
 impl Cartridge {
-    pub fn load(mut file: &mut File) -> Cartridge {
+    pub fn load<R: Read>(reader: &mut R) -> Result<Cartridge, CartridgeError> {
         let mut header: [u8; 16] = [0; 16];
-        file_to_buffer(&mut header, &mut file);
-
-        let header = NesHeader {
-            magic: [header[0], header[1], header[2], header[3]],
-            prg_size: header[4],
-            chr_size: header[5],
-            flags_6: header[6],
-            flags_7: header[7],
-            ram_size: header[8]
-        };
+        let n = read_to_buffer(&mut header, reader)?;
+        let magic = [header[0], header[1], header[2], header[3]];
+        if n < 4 || &magic != b"NES\x1a" {
+            return Err(CartridgeError::BadMagic(magic));
+        } else if n < 16 {
+            return Err(CartridgeError::TruncatedHeader);
+        }
+        let header = NesHeader::parse(&header)?;
+
+        if header.prg_rom_size < min_prg_rom_size(header.mapper()) {
+            return Err(CartridgeError::InvalidHeader("PRG ROM is too small"));
+        }
+        // Every board maps at least 8KB of pattern tables
+        if header.chr_rom_size > 0 && header.chr_rom_size < 0x2000 {
+            return Err(CartridgeError::InvalidHeader("CHR ROM is smaller than 8KB"));
+        }
+
+        let mut trainer = vec![0; if header.trainer() { 512 } else { 0 }];
+        let n = read_to_buffer(&mut trainer, reader)?;
+        if n < trainer.len() {
+            return Err(CartridgeError::TruncatedTrainer);
+        }
+
+        let prg_rom = read_section(reader, header.prg_rom_size)?;
+        if prg_rom.len() < header.prg_rom_size {
+            return Err(CartridgeError::TruncatedPrg { expected: header.prg_rom_size, actual: prg_rom.len() });
+        }
+
+        let mut chr_rom = read_section(reader, header.chr_rom_size)?;
+        if chr_rom.len() < header.chr_rom_size {
+            return Err(CartridgeError::TruncatedChr { expected: header.chr_rom_size, actual: chr_rom.len() });
+        }
 
-        let prg_len = header.prg_size as usize * 0x4000;
-        let mut prg_rom = vec![0; prg_len];
-        file_to_buffer(&mut prg_rom, &mut file);
+        // No CHR ROM means the pattern tables live in CHR RAM instead
+        if chr_rom.is_empty() {
+            let size = header.chr_ram_size + header.chr_nvram_size;
+            chr_rom = vec![0; std::cmp::max(size, 0x2000)];
+        }
 
-        let chr_len = header.chr_size as usize * 0x2000;
-        let mut chr_rom = vec![0; chr_len];
-        file_to_buffer(&mut chr_rom, &mut file);
+        let mut mapper: Box<dyn Mapper> = match header.mapper() {
+            0 => Box::new(Nrom::new(&header, prg_rom, chr_rom)),
+            1 => Box::new(Mmc1::new(&header, prg_rom, chr_rom)),
+            2 => Box::new(Uxrom::new(&header, prg_rom, chr_rom)),
+            3 => Box::new(Cnrom::new(&header, prg_rom, chr_rom)),
+            4 => Box::new(Mmc3::new(&header, prg_rom, chr_rom)),
+            7 => Box::new(Axrom::new(&header, prg_rom, chr_rom)),
+            66 => Box::new(Gxrom::new(&header, prg_rom, chr_rom)),
+            n => return Err(CartridgeError::UnsupportedMapper(n)),
+        };
 
-        Cartridge {
-            header: header,
-            prg: prg_rom,
-            chr: chr_rom,
-            ram: vec![0; 0x2000],
+        // The trainer is meant to be loaded at $7000-$71FF
+        if !trainer.is_empty() {
+            mapper.prg_ram()[0x1000..0x1200].copy_from_slice(&trainer);
         }
+
+        Ok(Cartridge {
+            header,
+            mapper: Rc::new(RefCell::new(mapper)),
+        })
+    }
+
+    // Copy of the PRG RAM, for battery backed carts to persist between sessions
+    pub fn save_ram(&self) -> Vec<u8> {
+        self.mapper.borrow_mut().prg_ram().to_vec()
+    }
+
+    pub fn write_save_ram<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        writer.write_all(self.mapper.borrow_mut().prg_ram())
+    }
+
+    // A save file shorter than the PRG RAM only overwrites the beginning of it
+    pub fn read_save_ram<R: Read>(&self, reader: &mut R) -> io::Result<()> {
+        let mut mapper = self.mapper.borrow_mut();
+        read_to_buffer(mapper.prg_ram(), reader)?;
+        Ok(())
     }
 }
 
 impl fmt::Display for NesHeader {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "PRG {}KB; CHR {}KB", self.prg_size as usize * 16, self.chr_size as usize * 8)
+        if self.nes2 {
+            write!(f, "NES 2.0; Mapper {}.{}; ", self.mapper, self.submapper)?;
+        } else {
+            write!(f, "iNES; Mapper {}; ", self.mapper)?;
+        }
+        write!(f, "PRG {}KB; CHR {}KB; {:?} mirroring; {:?}",
+            self.prg_rom_size / 1024, self.chr_rom_size / 1024, self.mirroring(), self.timing)
     }
 }
 
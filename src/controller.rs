@@ -1,7 +1,12 @@
 // FIXME: This is completely broken ATM.
 
+use std::io;
+use std::io::prelude::*;
+
 use sdl2::keyboard::Keycode;
 
+use savestate::{read_u8, write_u8};
+
 pub struct Controller {
     // pub buttons: [bool; 8],
     pub buttons: Vec<Keycode>,
@@ -65,4 +70,16 @@ impl Controller {
             self.index = 0;
         }
     }
+
+    // The buttons come from the host, only the shift register state is saved
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, self.index)?;
+        write_u8(writer, self.strobe)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.index = read_u8(reader)?;
+        self.strobe = read_u8(reader)?;
+        Ok(())
+    }
 }
@@ -1,6 +1,9 @@
 use std;
+use std::io;
+use std::io::prelude::*;
 
 use memory::CpuMemory;
+use savestate::*;
 
 /*
  * CPU Memory Map (http://wiki.nesdev.com/w/index.php/CPU_memory_map)
@@ -124,27 +127,45 @@ impl Cpu {
         self.pc = start;
     }
 
+    // Runs instructions until a scanline's worth of cycles went by
     pub fn step(&mut self) {
         loop {
-            let instruction = self.load_byte_and_inc_pc();
-            // print!("{:04x}: {:?}", self.pc - 1 - 0xc79e, self);
-            // print!("{:04x}: {:?}", self.pc - 1, self);
-            // print!(" Flags: {:08b}", self.p);
-            // println!(" Instruction: {:02x}", instruction);
-            // let pc = self.pc;
-            // println!(" Instr {:02x} {:02x} {:02x}", instruction, self.load_byte(pc), self.load_byte(pc + 1));
-            // for i in 0..31 {
-            //     print!("{:02x} ", self.ram.ram.val[i]);
-            // }
-            // println!("");
-
-            self.execute_instruction(instruction);
-            // TODO: Handle actual cycle count
-            self.cycle += CYCLES_PER_INSTRUCTION[instruction as usize] as u64;
+            self.step_instruction();
             if self.cycle > 113 { break; };
         }
     }
 
+    // Runs a single instruction, servicing a pending interrupt first.
+    // Returns the cycles it took, DMA stalls included.
+    pub fn step_instruction(&mut self) -> u64 {
+        let start = self.cycle;
+
+        if self.ram.nmi() {
+            self.nmi();
+        } else if !self.interrupt && self.ram.irq() {
+            self.irq();
+        }
+
+        let instruction = self.load_byte_and_inc_pc();
+        // print!("{:04x}: {:?}", self.pc - 1 - 0xc79e, self);
+        // print!("{:04x}: {:?}", self.pc - 1, self);
+        // print!(" Flags: {:08b}", self.p);
+        // println!(" Instruction: {:02x}", instruction);
+        // let pc = self.pc;
+        // println!(" Instr {:02x} {:02x} {:02x}", instruction, self.load_byte(pc), self.load_byte(pc + 1));
+        // for i in 0..31 {
+        //     print!("{:02x} ", self.ram.ram.val[i]);
+        // }
+        // println!("");
+
+        self.execute_instruction(instruction);
+        // TODO: Handle actual cycle count
+        self.cycle += CYCLES_PER_INSTRUCTION[instruction as usize] as u64;
+        let elapsed = self.cycle - start;
+        self.cycle += self.ram.step(elapsed);
+        self.cycle - start
+    }
+
     fn execute_instruction(&mut self, instruction: u8) {
         match instruction {
             // NOPs (all illegal except 0xEA)
@@ -897,11 +918,54 @@ impl Cpu {
         self.pc = pc + 1;
     }
 
+    // Maskable interrupt, ignored while the interrupt disable flag is set
+    pub fn irq(&mut self) {
+        if self.interrupt {
+            return;
+        }
+
+        let pc = self.pc;
+        self.push_word(pc);
+        let flags = self.get_flags() & !BREAK4_FLAG;
+        self.push_byte(flags);
+        self.sei();
+        self.pc = self.load_word(0xFFFE);
+        self.cycle += 7;
+    }
+
     pub fn nmi(&mut self) {
         let pc = self.pc;
         self.push_word(pc);
-        self.php();
+        let flags = self.get_flags() & !BREAK4_FLAG;
+        self.push_byte(flags);
+        self.sei();
         self.pc = self.load_word(0xFFFA);
+        self.cycle += 7;
+    }
+}
+
+impl Cpu {
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, self.a)?;
+        write_u8(writer, self.x)?;
+        write_u8(writer, self.y)?;
+        write_u8(writer, self.s)?;
+        write_u16(writer, self.pc)?;
+        write_u8(writer, self.get_flags())?;
+        write_u64(writer, self.cycle)?;
+        self.ram.save_state(writer)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.a = read_u8(reader)?;
+        self.x = read_u8(reader)?;
+        self.y = read_u8(reader)?;
+        self.s = read_u8(reader)?;
+        self.pc = read_u16(reader)?;
+        let flags = read_u8(reader)?;
+        self.set_flags(flags);
+        self.cycle = read_u64(reader)?;
+        self.ram.load_state(reader)
     }
 }
 
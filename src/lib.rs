@@ -1,7 +1,13 @@
 extern crate sdl2;
 
+pub mod apu;
 pub mod cartridge;
 pub mod controller;
 pub mod cpu;
+pub mod mapper;
 pub mod memory;
+pub mod ntsc;
+pub mod palette;
 pub mod ppu;
+pub mod savestate;
+pub mod scale;
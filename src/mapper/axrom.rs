@@ -0,0 +1,87 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_u8, write_u8};
+
+// Mapper 7
+// http://wiki.nesdev.com/w/index.php/AxROM
+//
+// Switchable 32KB PRG bank and single-screen mirroring selected by bit 4.
+pub struct Axrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+    bank: u8, // xxxM xPPP
+}
+
+impl Axrom {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Axrom {
+        Axrom {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            bank: 0,
+        }
+    }
+}
+
+impl Mapper for Axrom {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        } else if address < 0x8000 {
+            return self.prg_ram[address as usize - 0x6000];
+        }
+
+        // A 16KB image is mirrored, like on NROM
+        let bank_count = (self.prg.len() / 0x8000).max(1);
+        let bank = (self.bank as usize & 0x07) % bank_count;
+        self.prg[(bank * 0x8000 + (address as usize & 0x7FFF)) % self.prg.len()]
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.bank = value;
+        } else if address >= 0x6000 {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[address as usize & 0x1FFF]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            self.chr[address as usize & 0x1FFF] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        if self.bank & 0x10 == 0 {
+            Mirroring::SingleScreenLower
+        } else {
+            Mirroring::SingleScreenUpper
+        }
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_u8(writer, self.bank)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.bank = read_u8(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_u8, write_u8};
+
+// Mapper 3
+// http://wiki.nesdev.com/w/index.php/CNROM
+//
+// Fixed PRG like NROM, switchable 8KB CHR bank.
+pub struct Cnrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    chr_bank: u8,
+}
+
+impl Cnrom {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Cnrom {
+        Cnrom {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            mirroring: header.mirroring(),
+            chr_bank: 0,
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank_count = self.chr.len() / 0x2000;
+        let bank = self.chr_bank as usize % bank_count;
+        bank * 0x2000 + (address as usize & 0x1FFF)
+    }
+}
+
+impl Mapper for Cnrom {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        } else if address < 0x8000 {
+            return self.prg_ram[address as usize - 0x6000];
+        }
+        let len = self.prg.len();
+        self.prg[(address as usize - 0x8000) % len]
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.chr_bank = value;
+        } else if address >= 0x6000 {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_u8(writer, self.chr_bank)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.chr_bank = read_u8(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,92 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_u8, write_u8};
+
+// Mapper 66
+// http://wiki.nesdev.com/w/index.php/GxROM
+//
+// Switchable 32KB PRG bank and 8KB CHR bank, both set by the same register.
+pub struct Gxrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    bank: u8, // xxPP xxCC
+}
+
+impl Gxrom {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Gxrom {
+        Gxrom {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            mirroring: header.mirroring(),
+            bank: 0,
+        }
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let bank_count = self.chr.len() / 0x2000;
+        let bank = (self.bank as usize & 0x03) % bank_count;
+        bank * 0x2000 + (address as usize & 0x1FFF)
+    }
+}
+
+impl Mapper for Gxrom {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        } else if address < 0x8000 {
+            return self.prg_ram[address as usize - 0x6000];
+        }
+
+        // A 16KB image is mirrored, like on NROM
+        let bank_count = (self.prg.len() / 0x8000).max(1);
+        let bank = ((self.bank as usize >> 4) & 0x03) % bank_count;
+        self.prg[(bank * 0x8000 + (address as usize & 0x7FFF)) % self.prg.len()]
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.bank = value;
+        } else if address >= 0x6000 {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_u8(writer, self.bank)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.bank = read_u8(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,185 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_u8, write_u8};
+
+// Mapper 1
+// http://wiki.nesdev.com/w/index.php/MMC1
+//
+// Registers are written one bit at a time through a 5-bit shift register.
+// The fifth write copies the value into the register selected by bits 13-14
+// of the address of that last write.
+pub struct Mmc1 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+
+    shift: u8,
+    shift_count: u8,
+
+    control: u8, // CPPMM $8000-$9FFF
+    chr_bank_0: u8, // $A000-$BFFF
+    chr_bank_1: u8, // $C000-$DFFF
+    prg_bank: u8, // RPPPP $E000-$FFFF
+}
+
+impl Mmc1 {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Mmc1 {
+        Mmc1 {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+
+            shift: 0,
+            shift_count: 0,
+
+            // Power up with the last bank fixed at $C000 so the reset vector is reachable
+            control: 0x0C,
+            chr_bank_0: 0,
+            chr_bank_1: 0,
+            prg_bank: 0,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        match address {
+            0x8000..=0x9FFF => self.control = value,
+            0xA000..=0xBFFF => self.chr_bank_0 = value,
+            0xC000..=0xDFFF => self.chr_bank_1 = value,
+            _ => self.prg_bank = value,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_bank & 0x10 == 0
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let bank_count = self.prg.len() / 0x4000;
+
+        // SUROM and friends use bit 4 of the CHR bank to select a 256KB PRG half
+        let outer = if self.prg.len() > 0x40000 {
+            self.chr_bank_0 as usize & 0x10
+        } else {
+            0
+        };
+        let bank = outer | (self.prg_bank as usize & 0x0F);
+        let last = outer | 0x0F;
+        let upper = address >= 0xC000;
+
+        let bank = match (self.control >> 2) & 0x3 {
+            // 32KB mode, ignores the low bit of the bank number
+            0 | 1 => (bank & !1) | upper as usize,
+            // First bank fixed at $8000, switch $C000
+            2 => if upper { bank } else { outer },
+            // Switch $8000, last bank fixed at $C000
+            _ => if upper { last } else { bank },
+        };
+
+        (bank % bank_count) * 0x4000 + (address as usize & 0x3FFF)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        let offset = if self.control & 0x10 == 0 {
+            // One 8KB bank, ignores the low bit of the bank number
+            (self.chr_bank_0 as usize & 0x1E) * 0x1000 + (address as usize & 0x1FFF)
+        } else if address < 0x1000 {
+            self.chr_bank_0 as usize * 0x1000 + (address as usize & 0x0FFF)
+        } else {
+            self.chr_bank_1 as usize * 0x1000 + (address as usize & 0x0FFF)
+        };
+
+        offset % self.chr.len()
+    }
+}
+
+impl Mapper for Mmc1 {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address >= 0x8000 {
+            self.prg[self.prg_offset(address)]
+        } else if address >= 0x6000 && self.prg_ram_enabled() {
+            self.prg_ram[address as usize - 0x6000]
+        } else {
+            0
+        }
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address < 0x6000 {
+            return;
+        } else if address < 0x8000 {
+            if self.prg_ram_enabled() {
+                self.prg_ram[address as usize - 0x6000] = value;
+            }
+            return;
+        }
+
+        // Writing a value with bit 7 set resets the shift register
+        if value & 0x80 != 0 {
+            self.shift = 0;
+            self.shift_count = 0;
+            self.control |= 0x0C;
+            return;
+        }
+
+        self.shift |= (value & 1) << self.shift_count;
+        self.shift_count += 1;
+
+        if self.shift_count == 5 {
+            let shift = self.shift;
+            self.write_register(address, shift);
+            self.shift = 0;
+            self.shift_count = 0;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        match self.control & 0x3 {
+            0 => Mirroring::SingleScreenLower,
+            1 => Mirroring::SingleScreenUpper,
+            2 => Mirroring::Vertical,
+            _ => Mirroring::Horizontal,
+        }
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_u8(writer, self.shift)?;
+        write_u8(writer, self.shift_count)?;
+        write_u8(writer, self.control)?;
+        write_u8(writer, self.chr_bank_0)?;
+        write_u8(writer, self.chr_bank_1)?;
+        write_u8(writer, self.prg_bank)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.shift = read_u8(reader)?;
+        self.shift_count = read_u8(reader)?;
+        self.control = read_u8(reader)?;
+        self.chr_bank_0 = read_u8(reader)?;
+        self.chr_bank_1 = read_u8(reader)?;
+        self.prg_bank = read_u8(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,247 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_bool, read_bytes, read_u64, read_u8, write_bool, write_bytes, write_u64, write_u8};
+
+// Mapper 4
+// http://wiki.nesdev.com/w/index.php/MMC3
+//
+// 8KB PRG banks, 1KB/2KB CHR banks and a scanline counter that can raise IRQs.
+
+// A12 has to stay low for about 3 CPU cycles before a rise clocks the counter,
+// which filters out the short drops between sprite pattern fetches.
+// http://wiki.nesdev.com/w/index.php/MMC3#IRQ_Specifics
+const A12_FILTER_DOTS: u64 = 9;
+
+pub struct Mmc3 {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+
+    bank_select: u8, // CPMx xRRR $8000-$9FFE, even
+    registers: [u8; 8], // R0-R7 $8001-$9FFF, odd
+    prg_ram_protect: u8, // RWxx xxxx $A001-$BFFF, odd
+
+    irq_latch: u8,
+    irq_counter: u8,
+    irq_reload: bool,
+    irq_enabled: bool,
+    irq_pending: bool,
+
+    a12: bool,
+    a12_fell_at: u64, // PPU dot
+}
+
+impl Mmc3 {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Mmc3 {
+        Mmc3 {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            mirroring: header.mirroring(),
+
+            bank_select: 0,
+            registers: [0; 8],
+            prg_ram_protect: 0x80,
+
+            irq_latch: 0,
+            irq_counter: 0,
+            irq_reload: false,
+            irq_enabled: false,
+            irq_pending: false,
+
+            a12: false,
+            a12_fell_at: 0,
+        }
+    }
+
+    fn write_register(&mut self, address: u16, value: u8) {
+        let even = address & 1 == 0;
+
+        match address {
+            0x8000..=0x9FFF if even => self.bank_select = value,
+            0x8000..=0x9FFF => {
+                let index = self.bank_select & 0x7;
+                self.registers[index as usize] = value;
+            }
+            // Four-screen boards hardwire the nametables and ignore this register
+            0xA000..=0xBFFF if even && self.mirroring == Mirroring::FourScreen => (),
+            0xA000..=0xBFFF if even => {
+                self.mirroring = if value & 1 == 0 {
+                    Mirroring::Vertical
+                } else {
+                    Mirroring::Horizontal
+                };
+            }
+            0xA000..=0xBFFF => self.prg_ram_protect = value,
+            0xC000..=0xDFFF if even => self.irq_latch = value,
+            0xC000..=0xDFFF => {
+                self.irq_counter = 0;
+                self.irq_reload = true;
+            }
+            _ if even => {
+                self.irq_enabled = false;
+                self.irq_pending = false;
+            }
+            _ => self.irq_enabled = true,
+        }
+    }
+
+    fn prg_ram_enabled(&self) -> bool {
+        self.prg_ram_protect & 0x80 != 0
+    }
+
+    fn prg_ram_writable(&self) -> bool {
+        self.prg_ram_enabled() && self.prg_ram_protect & 0x40 == 0
+    }
+
+    fn prg_offset(&self, address: u16) -> usize {
+        let bank_count = self.prg.len() / 0x2000;
+        let second_last = bank_count - 2;
+        let swap = self.bank_select & 0x40 != 0;
+        let r6 = self.registers[6] as usize & 0x3F;
+        let r7 = self.registers[7] as usize & 0x3F;
+
+        let bank = match (address - 0x8000) / 0x2000 {
+            0 => if swap { second_last } else { r6 },
+            1 => r7,
+            2 => if swap { r6 } else { second_last },
+            _ => bank_count - 1,
+        };
+
+        (bank % bank_count) * 0x2000 + (address as usize & 0x1FFF)
+    }
+
+    fn chr_offset(&self, address: u16) -> usize {
+        // CHR A12 inversion swaps the 2KB and 1KB halves
+        let address = if self.bank_select & 0x80 != 0 {
+            address ^ 0x1000
+        } else {
+            address
+        };
+
+        let r = &self.registers;
+        let bank = match address / 0x400 {
+            0 => r[0] & 0xFE,
+            1 => r[0] | 1,
+            2 => r[1] & 0xFE,
+            3 => r[1] | 1,
+            4 => r[2],
+            5 => r[3],
+            6 => r[4],
+            _ => r[5],
+        };
+
+        (bank as usize * 0x400 + (address as usize & 0x3FF)) % self.chr.len()
+    }
+
+    // The counter is clocked on rising edges of PPU A12, which happens once
+    // per scanline when the background and sprites use different pattern tables.
+    fn clock_irq_counter(&mut self) {
+        if self.irq_counter == 0 || self.irq_reload {
+            self.irq_counter = self.irq_latch;
+            self.irq_reload = false;
+        } else {
+            self.irq_counter -= 1;
+        }
+
+        if self.irq_counter == 0 && self.irq_enabled {
+            self.irq_pending = true;
+        }
+    }
+}
+
+impl Mapper for Mmc3 {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address >= 0x8000 {
+            self.prg[self.prg_offset(address)]
+        } else if address >= 0x6000 && self.prg_ram_enabled() {
+            self.prg_ram[address as usize - 0x6000]
+        } else {
+            0
+        }
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.write_register(address, value);
+        } else if address >= 0x6000 && self.prg_ram_writable() {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[self.chr_offset(address)]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            let offset = self.chr_offset(address);
+            self.chr[offset] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn irq(&self) -> bool {
+        self.irq_pending
+    }
+
+    fn ppu_address(&mut self, address: u16, ppu_dot: u64) {
+        let a12 = address & 0x1000 != 0;
+
+        if a12 && !self.a12 && ppu_dot.saturating_sub(self.a12_fell_at) >= A12_FILTER_DOTS {
+            self.clock_irq_counter();
+        } else if !a12 && self.a12 {
+            self.a12_fell_at = ppu_dot;
+        }
+
+        self.a12 = a12;
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_bytes(writer, &self.registers)?;
+        write_bool(writer, self.mirroring == Mirroring::Horizontal)?;
+        write_u8(writer, self.bank_select)?;
+        write_u8(writer, self.prg_ram_protect)?;
+        write_u8(writer, self.irq_latch)?;
+        write_u8(writer, self.irq_counter)?;
+        write_bool(writer, self.irq_reload)?;
+        write_bool(writer, self.irq_enabled)?;
+        write_bool(writer, self.irq_pending)?;
+        write_bool(writer, self.a12)?;
+        write_u64(writer, self.a12_fell_at)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        read_bytes(reader, &mut self.registers)?;
+        let horizontal = read_bool(reader)?;
+        if self.mirroring != Mirroring::FourScreen {
+            self.mirroring = if horizontal { Mirroring::Horizontal } else { Mirroring::Vertical };
+        }
+        self.bank_select = read_u8(reader)?;
+        self.prg_ram_protect = read_u8(reader)?;
+        self.irq_latch = read_u8(reader)?;
+        self.irq_counter = read_u8(reader)?;
+        self.irq_reload = read_bool(reader)?;
+        self.irq_enabled = read_bool(reader)?;
+        self.irq_pending = read_bool(reader)?;
+        self.a12 = read_bool(reader)?;
+        self.a12_fell_at = read_u64(reader)?;
+        Ok(())
+    }
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::Mirroring;
+use savestate::{read_bytes, write_bytes};
+
+pub mod axrom;
+pub mod cnrom;
+pub mod gxrom;
+pub mod mmc1;
+pub mod mmc3;
+pub mod nrom;
+pub mod uxrom;
+
+pub use self::axrom::Axrom;
+pub use self::cnrom::Cnrom;
+pub use self::gxrom::Gxrom;
+pub use self::mmc1::Mmc1;
+pub use self::mmc3::Mmc3;
+pub use self::nrom::Nrom;
+pub use self::uxrom::Uxrom;
+
+// Reference: http://wiki.nesdev.com/w/index.php/Mapper
+//
+// A mapper sits between the cartridge memory and both buses. The CPU side
+// covers $4020-$FFFF, the PPU side covers the pattern tables ($0000-$1FFF).
+pub trait Mapper {
+    // CPU bus
+    fn prg_load(&mut self, address: u16) -> u8;
+    fn prg_store(&mut self, address: u16, value: u8);
+
+    // PPU bus
+    fn chr_load(&mut self, address: u16) -> u8;
+    fn chr_store(&mut self, address: u16, value: u8);
+
+    // PRG RAM mapped at $6000-$7FFF
+    fn prg_ram(&mut self) -> &mut [u8];
+
+    // Current nametable layout. Some mappers can change it at runtime.
+    fn mirroring(&self) -> Mirroring;
+
+    // Whether the mapper is currently asserting the CPU IRQ line
+    fn irq(&self) -> bool { false }
+
+    // Called with every address the PPU puts on its bus (pattern and nametable
+    // fetches, $2007 accesses) and the PPU dot count at that time, so boards can
+    // watch the address lines.
+    fn ppu_address(&mut self, _address: u16, _ppu_dot: u64) {}
+
+    // Called after every CPU instruction with the number of cycles it took
+    fn step(&mut self, _cpu_cycles: u64) {}
+
+    // Bank registers and RAM, for save states. ROM contents are not saved.
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()>;
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()>;
+}
+
+// RAM shared by every board. CHR is only saved when it is RAM.
+fn save_memory(writer: &mut dyn Write, prg_ram: &[u8], chr: &[u8], chr_ram: bool) -> io::Result<()> {
+    write_bytes(writer, prg_ram)?;
+    if chr_ram {
+        write_bytes(writer, chr)?;
+    }
+    Ok(())
+}
+
+fn load_memory(reader: &mut dyn Read, prg_ram: &mut [u8], chr: &mut [u8], chr_ram: bool) -> io::Result<()> {
+    read_bytes(reader, prg_ram)?;
+    if chr_ram {
+        read_bytes(reader, chr)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,73 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+
+// Mapper 0
+// http://wiki.nesdev.com/w/index.php/NROM
+//
+// No bank switching at all. 16KB PRG is mirrored in $C000-$FFFF.
+pub struct Nrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool, // Carts without CHR ROM come with 8KB of CHR RAM
+    mirroring: Mirroring,
+}
+
+impl Nrom {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Nrom {
+        Nrom {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            mirroring: header.mirroring(),
+        }
+    }
+}
+
+impl Mapper for Nrom {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        } else if address < 0x8000 {
+            return self.prg_ram[address as usize - 0x6000];
+        }
+        let len = self.prg.len();
+        self.prg[(address as usize - 0x8000) % len]
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if (0x6000..0x8000).contains(&address) {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[address as usize & 0x1FFF]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            self.chr[address as usize & 0x1FFF] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)
+    }
+}
@@ -0,0 +1,89 @@
+use std::io;
+use std::io::prelude::*;
+
+use cartridge::{Mirroring, NesHeader};
+use mapper::{load_memory, save_memory, Mapper};
+use savestate::{read_u8, write_u8};
+
+// Mapper 2
+// http://wiki.nesdev.com/w/index.php/UxROM
+//
+// Switchable 16KB bank at $8000, last bank fixed at $C000.
+pub struct Uxrom {
+    prg: Vec<u8>,
+    chr: Vec<u8>,
+    prg_ram: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    prg_bank: u8,
+}
+
+impl Uxrom {
+    pub fn new(header: &NesHeader, prg: Vec<u8>, chr: Vec<u8>) -> Uxrom {
+        Uxrom {
+            prg,
+            chr,
+            prg_ram: vec![0; 0x2000],
+            chr_ram: header.chr_rom_size == 0,
+            mirroring: header.mirroring(),
+            prg_bank: 0,
+        }
+    }
+}
+
+impl Mapper for Uxrom {
+    fn prg_load(&mut self, address: u16) -> u8 {
+        if address < 0x6000 {
+            return 0;
+        } else if address < 0x8000 {
+            return self.prg_ram[address as usize - 0x6000];
+        }
+
+        let bank_count = self.prg.len() / 0x4000;
+        let bank = if address < 0xC000 {
+            self.prg_bank as usize % bank_count
+        } else {
+            bank_count - 1
+        };
+
+        self.prg[bank * 0x4000 + (address as usize & 0x3FFF)]
+    }
+
+    fn prg_store(&mut self, address: u16, value: u8) {
+        if address >= 0x8000 {
+            self.prg_bank = value;
+        } else if address >= 0x6000 {
+            self.prg_ram[address as usize - 0x6000] = value;
+        }
+    }
+
+    fn chr_load(&mut self, address: u16) -> u8 {
+        self.chr[address as usize & 0x1FFF]
+    }
+
+    fn chr_store(&mut self, address: u16, value: u8) {
+        if self.chr_ram {
+            self.chr[address as usize & 0x1FFF] = value;
+        }
+    }
+
+    fn prg_ram(&mut self) -> &mut [u8] {
+        &mut self.prg_ram
+    }
+
+    fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        save_memory(writer, &self.prg_ram, &self.chr, self.chr_ram)?;
+        write_u8(writer, self.prg_bank)?;
+        Ok(())
+    }
+
+    fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        load_memory(reader, &mut self.prg_ram, &mut self.chr, self.chr_ram)?;
+        self.prg_bank = read_u8(reader)?;
+        Ok(())
+    }
+}
@@ -1,8 +1,12 @@
 use std;
+use std::io;
+use std::io::prelude::*;
 
+use apu::Apu;
 use cartridge::Cartridge;
 use controller::Controller;
 use ppu::Ppu;
+use savestate::{read_bytes, write_bytes};
 
 trait Memory {
     fn load(address: u16) -> u8;
@@ -13,8 +17,9 @@ pub struct CpuMemory {
     pub ram: Ram,
     pub cartridge: Cartridge,
     pub ppu: Ppu,
-    pub controller: Controller
-    // TODO: apu
+    pub controller: Controller,
+    pub apu: Apu,
+    controller_read: bool, // $4016 was read since the last step
 }
 
 impl CpuMemory {
@@ -23,7 +28,9 @@ impl CpuMemory {
             cartridge: cartridge,
             ppu: ppu,
             controller: controller,
-            ram: Ram::new()
+            apu: Apu::new(),
+            ram: Ram::new(),
+            controller_read: false,
         }
     }
 
@@ -32,19 +39,19 @@ impl CpuMemory {
             return self.ram.load(address);
         } else if address < 0x4000 {
             return self.ppu.load(0x2000 + address % 8);
+        } else if address == 0x4015 {
+            return self.apu.read_status();
         } else if address == 0x4016 {
+            self.controller_read = true;
             return self.controller.load(address);
         } else if address < 0x4018 {
-            // TODO: APU
+            // TODO: Second controller
             return 0;
-        } else if address < 0x6000 {
-            println!("Reading from memory at {:04x} - Not implemented yet", address);
+        } else if address < 0x4020 {
+            // CPU test mode, disabled on retail units
             return 0;
-            //panic!("Address loading at {:04x} not implemented", address);
-        } else if self.cartridge.header.prg_size > 1 {
-            return self.cartridge.prg[address as usize & 0x7FFF];
         } else {
-            return self.cartridge.prg[address as usize & 0x3FFF];
+            return self.cartridge.mapper.borrow_mut().prg_load(address);
         };
     }
 
@@ -58,17 +65,63 @@ impl CpuMemory {
         } else if address == 0x4016 {
             self.controller.store(address, value);
         } else if address < 0x4018 {
-            // TODO: APU
-        } else if address < 0x6000 {
-            println!("Writing {:02x} to memory at {:04x} - Not implemented yet", value, address);
-            //panic!("Address storing at {:04x} not implemented", address);
+            self.apu.store(address, value);
+        } else if address < 0x4020 {
+            // CPU test mode, disabled on retail units
         } else {
-            // FIXME: Yeah. This should go to a mapper. This does not work correctly;
-            // Can you even store in the PRG anyway...?
-            self.cartridge.prg[address as usize & 0x3FFF] = value;
+            self.cartridge.mapper.borrow_mut().prg_store(address, value);
         };
     }
 
+    // Called after every instruction so that the PPU, APU and cartridge can keep track of time.
+    // Returns the number of cycles the CPU was stalled by DMC sample fetches.
+    pub fn step(&mut self, cycles: u64) -> u64 {
+        let stall = {
+            // Samples always live in $8000-$FFFF
+            let mapper = &self.cartridge.mapper;
+            self.apu.step(cycles, |address| mapper.borrow_mut().prg_load(address))
+        };
+
+        // A DMC fetch colliding with a controller read clocks the shift register one
+        // extra time, dropping a bit. We don't know exactly which cycle the read
+        // happened on, so assume any fetch during that instruction collides.
+        // http://wiki.nesdev.com/w/index.php/APU_DMC#Conflict_with_controller_and_PPU_read
+        if stall > 0 && self.controller_read {
+            self.controller.load(0x4016);
+        }
+        self.controller_read = false;
+
+        self.ppu.step(cycles + stall);
+        self.cartridge.mapper.borrow_mut().step(cycles + stall);
+        stall
+    }
+
+    // Whether the PPU raised an NMI since last time
+    pub fn nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+
+    // State of the (active low, wired-OR) IRQ line
+    pub fn irq(&self) -> bool {
+        self.apu.irq() || self.cartridge.mapper.borrow().irq()
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_bytes(writer, &self.ram.val[0..0x800])?;
+        self.ppu.save_state(writer)?;
+        self.controller.save_state(writer)?;
+        self.apu.save_state(writer)?;
+        self.cartridge.mapper.borrow().save_state(writer)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        read_bytes(reader, &mut self.ram.val[0..0x800])?;
+        self.ppu.load_state(reader)?;
+        self.controller.load_state(reader)?;
+        self.apu.load_state(reader)?;
+        self.cartridge.mapper.borrow_mut().load_state(reader)
+    }
+
     fn dma(&mut self, start: u16) {
         let page = start * 0x100;
 
@@ -0,0 +1,207 @@
+use palette::{self, NtscSettings, EMPHASIZED_SIZE};
+use ppu::{SCREEN_HEIGHT, SCREEN_WIDTH};
+
+// Software simulation of the PPU video signal going through a TV. The limited
+// bandwidth blends dithering together and the luma and chroma bleed into each
+// other, which some games count on.
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+
+// The PPU spends 8 master clocks on a pixel, each one a phase of the 12 phase subcarrier
+const SAMPLES_PER_PIXEL: usize = 8;
+const LINE_SAMPLES: usize = SCREEN_WIDTH * SAMPLES_PER_PIXEL;
+const SAMPLES_PER_OUTPUT: usize = 4;
+
+// Every PPU pixel turns into two output pixels
+pub const OUTPUT_WIDTH: usize = LINE_SAMPLES / SAMPLES_PER_OUTPUT;
+
+// A scanline is 341 * 8 master clocks long, so each one starts 4 phases later
+const LINE_PHASE_STEP: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NtscPreset {
+    Composite, // Luma and chroma share the signal and bleed into each other
+    SVideo, // Separate luma and chroma, only the chroma is blurry
+    Rgb, // No signal artifacts at all
+}
+
+impl NtscPreset {
+    pub fn parse(name: &str) -> Option<NtscPreset> {
+        match name {
+            "composite" => Some(NtscPreset::Composite),
+            "svideo" => Some(NtscPreset::SVideo),
+            "rgb" => Some(NtscPreset::Rgb),
+            _ => None,
+        }
+    }
+
+    // Width of the luma and chroma filters, in samples
+    fn windows(self) -> (usize, usize) {
+        match self {
+            NtscPreset::Composite => (8, 24),
+            NtscPreset::SVideo => (4, 12),
+            NtscPreset::Rgb => (12, 12),
+        }
+    }
+}
+
+pub struct NtscFilter {
+    preset: NtscPreset,
+    settings: NtscSettings,
+    colors: Vec<u32>, // Used as is by the RGB preset
+
+    levels: Vec<[f64; 12]>, // Signal of every color at every phase
+    luma: Vec<f64>, // Average signal of every color
+
+    // Frames alternately start 4 and 8 phases later, because of the dot skipped on
+    // odd frames, so the artifacts flip back and forth between two patterns
+    frame_phase: usize,
+
+    // Running sums of the decoded line, so each output pixel is a difference
+    y_sums: Vec<f64>,
+    u_sums: Vec<f64>,
+    v_sums: Vec<f64>,
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscPreset) -> NtscFilter {
+        let levels: Vec<[f64; 12]> = (0..EMPHASIZED_SIZE).map(|color| {
+            let mut levels = [0.0; 12];
+            for (phase, level) in levels.iter_mut().enumerate() {
+                *level = palette::signal_level(color, phase);
+            }
+            levels
+        }).collect();
+        let luma = levels.iter().map(|levels| levels.iter().sum::<f64>() / 12.0).collect();
+
+        let settings = NtscSettings::new();
+
+        NtscFilter {
+            preset,
+            settings,
+            colors: palette::generate(&settings),
+
+            levels,
+            luma,
+
+            frame_phase: 0,
+
+            y_sums: vec![0.0; LINE_SAMPLES + 1],
+            u_sums: vec![0.0; LINE_SAMPLES + 1],
+            v_sums: vec![0.0; LINE_SAMPLES + 1],
+        }
+    }
+
+    pub fn preset(&self) -> NtscPreset {
+        self.preset
+    }
+
+    pub fn settings(&self) -> NtscSettings {
+        self.settings
+    }
+
+    // Also regenerates the colors of the RGB preset
+    pub fn set_settings(&mut self, settings: NtscSettings) {
+        self.settings = settings;
+        self.colors = palette::generate(&settings);
+    }
+
+    // Colors for the RGB preset instead of the generated ones, e.g. from a .pal file.
+    // The other presets simulate the signal and have no use for them.
+    pub fn set_palette(&mut self, colors: Vec<u32>) {
+        assert_eq!(colors.len(), EMPHASIZED_SIZE);
+        self.colors = colors;
+    }
+
+    // Turns an indexed frame (see Ppu::frame) into OUTPUT_WIDTH * SCREEN_HEIGHT RGB24 pixels
+    pub fn filter(&mut self, frame: &[u16], out: &mut [u8]) {
+        if self.preset == NtscPreset::Rgb {
+            self.filter_rgb(frame, out);
+            return;
+        }
+
+        let mut carrier = [(0.0, 0.0); 12];
+        for (phase, c) in carrier.iter_mut().enumerate() {
+            let angle = palette::subcarrier_angle(phase as f64, &self.settings);
+            *c = (angle.cos(), angle.sin());
+        }
+
+        for (y, line) in frame.chunks(SCREEN_WIDTH).take(SCREEN_HEIGHT).enumerate() {
+            let line_phase = self.frame_phase + y * LINE_PHASE_STEP;
+            self.encode_line(line, line_phase, &carrier);
+
+            let row = &mut out[y * OUTPUT_WIDTH * 3..(y + 1) * OUTPUT_WIDTH * 3];
+            self.decode_line(row);
+        }
+
+        self.frame_phase = (self.frame_phase + 4) % 8;
+    }
+
+    // Generates the signal of a scanline and demodulates it, keeping running sums
+    fn encode_line(&mut self, line: &[u16], line_phase: usize, carrier: &[(f64, f64); 12]) {
+        let separate = self.preset == NtscPreset::SVideo;
+        let mut y_sum = 0.0;
+        let mut u_sum = 0.0;
+        let mut v_sum = 0.0;
+
+        for (x, &color) in line.iter().enumerate() {
+            let color = color as usize;
+            for sample in 0..SAMPLES_PER_PIXEL {
+                let i = x * SAMPLES_PER_PIXEL + sample;
+                let phase = (line_phase + i) % 12;
+                let signal = self.levels[color][phase];
+
+                // S-Video carries the luma on its own wire
+                let (luma, chroma) = if separate {
+                    (self.luma[color], signal - self.luma[color])
+                } else {
+                    (signal, signal)
+                };
+
+                y_sum += luma;
+                u_sum += chroma * carrier[phase].0;
+                v_sum += chroma * carrier[phase].1;
+                self.y_sums[i + 1] = y_sum;
+                self.u_sums[i + 1] = u_sum;
+                self.v_sums[i + 1] = v_sum;
+            }
+        }
+    }
+
+    // Low-pass filters the luma and chroma around each output pixel
+    fn decode_line(&self, row: &mut [u8]) {
+        let (luma_window, chroma_window) = self.preset.windows();
+
+        // Outside of the picture the signal is black
+        let window = |sums: &[f64], center: usize, width: usize| {
+            let start = center.saturating_sub(width / 2);
+            let end = (center + width / 2).min(LINE_SAMPLES);
+            (sums[end] - sums[start]) / width as f64
+        };
+
+        for (i, pixel) in row.chunks_mut(3).enumerate() {
+            let center = i * SAMPLES_PER_OUTPUT + SAMPLES_PER_OUTPUT / 2;
+            let y = window(&self.y_sums, center, luma_window);
+            // Synchronous demodulation doubles the chroma amplitude
+            let u = 2.0 * window(&self.u_sums, center, chroma_window);
+            let v = 2.0 * window(&self.v_sums, center, chroma_window);
+
+            let color = palette::yuv_to_rgb(y, u, v, &self.settings);
+            pixel[0] = (color >> 16) as u8;
+            pixel[1] = (color >> 8) as u8;
+            pixel[2] = color as u8;
+        }
+    }
+
+    // Every pixel gets its exact color, by default decoded over a whole subcarrier cycle
+    fn filter_rgb(&self, frame: &[u16], out: &mut [u8]) {
+        let colors = &self.colors;
+        let pixels = frame.iter().take(SCREEN_WIDTH * SCREEN_HEIGHT);
+
+        for (pixel, &index) in out.chunks_mut(6).zip(pixels) {
+            let color = colors[index as usize];
+            let rgb = [(color >> 16) as u8, (color >> 8) as u8, color as u8];
+            pixel[..3].copy_from_slice(&rgb);
+            pixel[3..].copy_from_slice(&rgb);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use std::error::Error;
+use std::f64::consts::PI;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+// http://wiki.nesdev.com/w/index.php/PPU_palettes
+
+// Palette inspired by fogleman/nes
+pub const PALETTE_RGB: [u32; 64] = [
+    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E,
+    0x6E0040, 0x6C0600, 0x561D00, 0x333500, 0x0B4800,
+    0x005200, 0x004F08, 0x00404D, 0x000000, 0x000000,
+    0x000000, 0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE,
+    0xA01ACC, 0xB71E7B, 0xB53120, 0x994E00, 0x6B6D00,
+    0x388700, 0x0C9300, 0x008F32, 0x007C8D, 0x000000,
+    0x000000, 0x000000, 0xFFFEFF, 0x64B0FF, 0x9290FF,
+    0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22,
+    0xBCBE00, 0x88D800, 0x5CE430, 0x45E082, 0x48CDDE,
+    0x4F4F4F, 0x000000, 0x000000, 0xFFFEFF, 0xC0DFFF,
+    0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5,
+    0xF7D8A5, 0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC,
+    0xB5EBF2, 0xB8B8B8, 0x000000, 0x000000,
+];
+
+// Colors for every combination of the PPUMASK emphasis bits, 64 per combination.
+// Index with `emphasis << 6 | color`, where emphasis is PPUMASK bits 5-7 (BGR).
+pub const EMPHASIZED_SIZE: usize = 8 * 64;
+
+// Emphasizing a component darkens the other two, by roughly 18%.
+// Setting all three bits darkens everything.
+// http://wiki.nesdev.com/w/index.php/NTSC_video#Color_Tint_Bits
+const EMPHASIS_ATTENUATION: f64 = 0.816;
+
+// Builds the full emphasized palette out of 64 base colors
+pub fn emphasized(base: &[u32; 64]) -> Vec<u32> {
+    let mut palette = Vec::with_capacity(EMPHASIZED_SIZE);
+
+    for emphasis in 0..8 {
+        for (index, &color) in base.iter().enumerate() {
+            // Columns $xE and $xF are black no matter what
+            if index & 0x0E == 0x0E {
+                palette.push(color);
+                continue;
+            }
+
+            let mut rgb = 0;
+            for component in 0..3 {
+                // Red is the highest byte, and emphasis bit 0
+                let shift = 16 - component * 8;
+                let mut value = ((color >> shift) & 0xFF) as f64;
+                if emphasis & !(1 << component) != 0 {
+                    value *= EMPHASIS_ATTENUATION;
+                }
+                rgb |= (value.round() as u32) << shift;
+            }
+            palette.push(rgb);
+        }
+    }
+
+    palette
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    BadSize(usize),
+    Io(io::Error),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            PaletteError::BadSize(n) =>
+                write!(f, "expected 192 or 1536 bytes of RGB colors, got {}", n),
+            PaletteError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for PaletteError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            PaletteError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for PaletteError {
+    fn from(e: io::Error) -> PaletteError {
+        PaletteError::Io(e)
+    }
+}
+
+// Reads a .pal file: 64 RGB triplets, or 512 when it comes with the emphasized colors
+pub fn load<R: Read>(reader: &mut R) -> Result<Vec<u32>, PaletteError> {
+    let mut bytes = Vec::new();
+    reader.read_to_end(&mut bytes)?;
+
+    if bytes.len() != 192 && bytes.len() != 1536 {
+        return Err(PaletteError::BadSize(bytes.len()));
+    }
+
+    let colors: Vec<u32> = bytes.chunks(3).map(|rgb| {
+        (rgb[0] as u32) << 16 | (rgb[1] as u32) << 8 | rgb[2] as u32
+    }).collect();
+
+    if colors.len() == 64 {
+        let mut base = [0; 64];
+        base.copy_from_slice(&colors);
+        Ok(emphasized(&base))
+    } else {
+        Ok(colors)
+    }
+}
+
+// Knobs of the TV decoding the composite signal
+#[derive(Clone, Copy)]
+pub struct NtscSettings {
+    pub hue: f64, // Degrees
+    pub saturation: f64,
+    pub contrast: f64,
+    pub brightness: f64, // Added to the luma, 0 leaves it alone
+    pub gamma: f64, // Of the display, the signal is assumed to be 2.2
+}
+
+impl NtscSettings {
+    pub fn new() -> NtscSettings {
+        NtscSettings {
+            hue: 0.0,
+            saturation: 1.0,
+            contrast: 1.0,
+            brightness: 0.0,
+            gamma: 2.2,
+        }
+    }
+}
+
+impl Default for NtscSettings {
+    fn default() -> NtscSettings {
+        NtscSettings::new()
+    }
+}
+
+// Signal voltages relative to sync, for the low and high halves of the square wave
+// http://wiki.nesdev.com/w/index.php/NTSC_video#Brightness_Levels
+const SIGNAL_LOW: [f64; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f64; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f64 = 0.518;
+const WHITE: f64 = 1.962;
+const SIGNAL_ATTENUATION: f64 = 0.746;
+
+// Level of the composite signal for a color, at one of the 12 phases of the color
+// subcarrier. 0 is black and 1 white.
+pub fn signal_level(color: usize, phase: usize) -> f64 {
+    let hue = color & 0x0F;
+    let level = if hue >= 0x0E { 1 } else { (color >> 4) & 3 };
+    let emphasis = color >> 6;
+    let in_phase = |hue: usize| (hue + phase) % 12 < 6;
+
+    let signal = match hue {
+        0x00 => SIGNAL_HIGH[level],
+        0x0D..=0x0F => SIGNAL_LOW[level],
+        _ if in_phase(hue) => SIGNAL_HIGH[level],
+        _ => SIGNAL_LOW[level],
+    };
+
+    // Each emphasis bit attenuates the signal during the phase opposite its color
+    let attenuated = (emphasis & 1 != 0 && in_phase(0x0C))
+        || (emphasis & 2 != 0 && in_phase(0x04))
+        || (emphasis & 4 != 0 && in_phase(0x08));
+
+    let signal = if attenuated { signal * SIGNAL_ATTENUATION } else { signal };
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+// Angle of the subcarrier at a phase, as seen by the decoder.
+// Color $x8 is in phase with the color burst, which sits at 180 degrees.
+pub fn subcarrier_angle(phase: f64, settings: &NtscSettings) -> f64 {
+    -PI * (phase - 0.5) / 6.0 + settings.hue.to_radians()
+}
+
+// Turns the demodulated signal into a color, after the TV adjustments
+pub fn yuv_to_rgb(y: f64, u: f64, v: f64, settings: &NtscSettings) -> u32 {
+    let y = y * settings.contrast + settings.brightness;
+    let u = u * settings.saturation * settings.contrast;
+    let v = v * settings.saturation * settings.contrast;
+
+    let r = y + 1.13983 * v;
+    let g = y - 0.39465 * u - 0.58060 * v;
+    let b = y + 2.03211 * u;
+
+    let component = |c: f64| {
+        let c = c.clamp(0.0, 1.0).powf(2.2 / settings.gamma);
+        (c * 255.0).round() as u32
+    };
+    component(r) << 16 | component(g) << 8 | component(b)
+}
+
+// Computes the 512 colors by decoding the signal the PPU generates, like a TV would
+// http://wiki.nesdev.com/w/index.php/NTSC_video
+pub fn generate(settings: &NtscSettings) -> Vec<u32> {
+    (0..EMPHASIZED_SIZE).map(|color| {
+        let mut y = 0.0;
+        let mut u = 0.0;
+        let mut v = 0.0;
+
+        for phase in 0..12 {
+            let signal = signal_level(color, phase);
+            let angle = subcarrier_angle(phase as f64, settings);
+            y += signal;
+            u += signal * angle.cos();
+            v += signal * angle.sin();
+        }
+
+        // Synchronous demodulation doubles the chroma amplitude
+        yuv_to_rgb(y / 12.0, u / 6.0, v / 6.0, settings)
+    }).collect()
+}
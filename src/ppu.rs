@@ -1,30 +1,15 @@
 use std;
+use std::io;
+use std::io::prelude::*;
 
-use cartridge::Cartridge;
+use cartridge::{Cartridge, Mirroring};
+use palette;
 
-// http://wiki.nesdev.com/w/index.php/PPU_programmer_reference
+pub const SCREEN_WIDTH: usize = 256;
+pub const SCREEN_HEIGHT: usize = 240;
+use savestate::*;
 
-pub struct PpuResult {
-    pub new_frame: bool,
-    pub nmi: bool
-}
-
-// Palette inspired by fogleman/nes
-const PALETTE_RGB: [u32; 64] = [
-    0x666666, 0x002A88, 0x1412A7, 0x3B00A4, 0x5C007E,
-    0x6E0040, 0x6C0600, 0x561D00, 0x333500, 0x0B4800,
-    0x005200, 0x004F08, 0x00404D, 0x000000, 0x000000,
-    0x000000, 0xADADAD, 0x155FD9, 0x4240FF, 0x7527FE,
-    0xA01ACC, 0xB71E7B, 0xB53120, 0x994E00, 0x6B6D00,
-    0x388700, 0x0C9300, 0x008F32, 0x007C8D, 0x000000,
-    0x000000, 0x000000, 0xFFFEFF, 0x64B0FF, 0x9290FF,
-    0xC676FF, 0xF36AFF, 0xFE6ECC, 0xFE8170, 0xEA9E22,
-    0xBCBE00, 0x88D800, 0x5CE430, 0x45E082, 0x48CDDE,
-    0x4F4F4F, 0x000000, 0x000000, 0xFFFEFF, 0xC0DFFF,
-    0xD3D2FF, 0xE8C8FF, 0xFBC2FF, 0xFEC4EA, 0xFECCC5,
-    0xF7D8A5, 0xE4E594, 0xCFEF96, 0xBDF4AB, 0xB3F3CC,
-    0xB5EBF2, 0xB8B8B8, 0x000000, 0x000000,
-];
+// http://wiki.nesdev.com/w/index.php/PPU_programmer_reference
 
 struct Sprite {
     pub x: u8,
@@ -33,9 +18,10 @@ struct Sprite {
     pub attributes: u8 // vhp---PP
 }
 
+// Pattern table addresses of the sprite tiles
 enum Tiles {
     Tiles8(u16),
-    Tiles16(u16, u16)
+    Tiles16(u16, u16) // Top, bottom
 }
 
 // // http://wiki.nesdev.com/w/index.php/PPU_OAM
@@ -43,19 +29,20 @@ impl Sprite {
     pub fn palette(&self) -> u8 {
         (self.attributes & 0x3) + 4
     }
+    fn behind_background(&self) -> bool { (self.attributes & 0x20) != 0 }
     fn horizontal_flip(&self) -> bool { (self.attributes & 0x40) != 0 }
     fn vertical_flip(&self) -> bool { (self.attributes & 0x80) != 0 }
 
     pub fn get_tiles(&self, ppu: &Ppu) -> Tiles {
         if ppu.sprite_size() == 8 {
-            Tiles::Tiles8(self.index as u16 | ppu.sprite_pattern_table_address())
+            Tiles::Tiles8(ppu.sprite_pattern_table_address() + ((self.index as u16) << 4))
         } else {
             // Ignore PPUCTRL and take bit 0 instead
-            let mut address: u16 = self.index as u16 & !1;
+            let mut address = (self.index as u16 & !1) << 4;
             if (self.index & 1) != 0 {
                 address += 0x1000;
             }
-            Tiles::Tiles16(address as u16, address as u16 + 1)
+            Tiles::Tiles16(address, address + 16)
         }
     }
 }
@@ -90,8 +77,6 @@ struct Registers {
     status: u8, // VSO- ---- 0x2002
     oam_address: u8, // aaaa aaaa 0x2003
     oam_data: u8, // dddd dddd 0x2004
-    scroll: u8, // xxxx xxxx 0x2005
-    address: u16, // aaaa aaaa 0x2006
 }
 
 impl Registers {
@@ -102,8 +87,6 @@ impl Registers {
             status: 0x80,
             oam_address: 0,
             oam_data: 0,
-            scroll: 0,
-            address: 0,
         }
     }
 
@@ -116,44 +99,105 @@ pub struct Ppu {
     regs: Registers,
     vram: Vram,
 
-    // whether to write to the high or low byte
-    vram_rw_high: bool,
-    scroll_x: u8,
-    scroll_y: u8,
-    next_scroll_x: bool,
+    // Internal registers shared by $2000, $2002, $2005 and $2006
+    // http://wiki.nesdev.com/w/index.php/PPU_scrolling
+    v: u16, // Current VRAM address: yyy NN YYYYY XXXXX
+    t: u16, // Temporary VRAM address, the top left onscreen tile
+    x: u8, // Fine X scroll
+    w: bool, // First or second write toggle
     data_buffer: u8,
 
-    pub cycle: u64,
+    pub cycle: u64, // Dot in the scanline, 0-340
     pub new_frame: bool,
-    pub frame_content: Vec<u8>, //[u8; 256 * 240 * 3],
-    scanline: u16, // 0-239 is visible, 240 post, 241-260 vblank, 261 pre
+    frame: Vec<u16>, // Palette index with the emphasis bits, see palette::EMPHASIZED_SIZE
+    pub scanline: u16, // 0-239 is visible, 240 post, 241-260 vblank, 261 pre
     pub frames: u64,
+    odd_frame: bool,
+    dots: u64, // Since power on, lets mappers time the address lines
+
+    // NMI is raised on the rising edge of VBlank && NMI enable
+    nmi_line: bool,
+    nmi_pending: bool,
+
+    // Background tile being fetched, then fed 8 pixels at a time to the shift registers
+    // http://wiki.nesdev.com/w/index.php/PPU_rendering
+    name_table_byte: u8,
+    attribute_byte: u8, // 2 bits, already picked from the attribute quadrant
+    pattern_low: u8,
+    pattern_high: u8,
+    pattern_shift_low: u16,
+    pattern_shift_high: u16,
+    attribute_shift_low: u16,
+    attribute_shift_high: u16,
+
+    // Sprites in range of the next line, picked from OAM during this one
+    secondary_oam: [u8; 32],
+    secondary_count: usize,
+    sprite_zero_next: bool, // Sprite 0 made it into secondary OAM
+
+    // Sprites of the scanline being drawn, fetched during the previous one
+    sprite_count: usize,
+    sprite_zero_line: bool, // Slot 0 holds sprite 0
+    sprite_patterns: [u32; 8], // 8 pixels of 4 bits: palette (2) and color (2)
+    sprite_positions: [u8; 8],
+    sprite_priorities: [bool; 8], // Behind the background
 
     palettes: [u8; 32],
+    palette: Vec<u32>, // RGB, with every emphasis combination
     name_tables: Vec<u8>,
     pub oam_data: [u8; 256]
 }
 
 impl Ppu {
     pub fn new(cartridge: Cartridge) -> Ppu {
+        let name_tables_size = match cartridge.header.mirroring() {
+            Mirroring::FourScreen => 0x1000,
+            _ => 0x800,
+        };
+
         Ppu {
             cartridge: cartridge,
             regs: Registers::new(),
             vram: Vram { val: vec![0; 0x800] },
-            vram_rw_high: true,
-            scroll_x: 0,
-            scroll_y: 0,
-            next_scroll_x: true,
+            v: 0,
+            t: 0,
+            x: 0,
+            w: false,
             data_buffer: 0,
 
             cycle: 340,
             new_frame: false,
-            frame_content: vec![0; 256 * 240 * 3],
+            frame: vec![0; SCREEN_WIDTH * SCREEN_HEIGHT],
             scanline: 240,
             frames: 0,
+            odd_frame: false,
+            dots: 0,
+
+            nmi_line: false,
+            nmi_pending: false,
+
+            name_table_byte: 0,
+            attribute_byte: 0,
+            pattern_low: 0,
+            pattern_high: 0,
+            pattern_shift_low: 0,
+            pattern_shift_high: 0,
+            attribute_shift_low: 0,
+            attribute_shift_high: 0,
+
+            secondary_oam: [0xFF; 32],
+            secondary_count: 0,
+            sprite_zero_next: false,
+
+            sprite_count: 0,
+            sprite_zero_line: false,
+            sprite_patterns: [0; 8],
+            sprite_positions: [0; 8],
+            sprite_priorities: [false; 8],
 
             palettes: [0; 32],
-            name_tables: vec![0; 2048],
+            palette: palette::emphasized(&palette::PALETTE_RGB),
+            name_tables: vec![0; name_tables_size],
             oam_data: [0; 256]
         }
     }
@@ -161,30 +205,205 @@ impl Ppu {
     pub fn reset(&mut self) {
         self.cycle = 340;
         self.scanline = 240;
+        self.odd_frame = false;
         self.regs.control = 0;
         self.regs.mask = 0;
         self.regs.oam_address = 0;
+        self.w = false;
+    }
+
+    // Maps one of the four logical name tables onto the 2KB of VRAM,
+    // depending on the mirroring currently selected by the cartridge.
+    // Four-screen carts bring 2KB of extra VRAM so nothing is mirrored.
+    // http://wiki.nesdev.com/w/index.php/Mirroring#Nametable_Mirroring
+    fn name_table_index(&self, address: u16) -> usize {
+        let address = (address as usize - 0x2000) % 0x1000; // $3000-$3EFF mirrors $2000-$2EFF
+        let table = address / 0x400;
+        let offset = address % 0x400;
+
+        let physical = match self.cartridge.mapper.borrow().mirroring() {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+            Mirroring::FourScreen => table,
+        };
+
+        physical * 0x400 + offset
+    }
+
+    // Replaces the 512 RGB colors pixels are drawn with, see the palette module
+    pub fn set_palette(&mut self, colors: Vec<u32>) {
+        assert_eq!(colors.len(), palette::EMPHASIZED_SIZE);
+        self.palette = colors;
+    }
+
+    // The last frame as palette indexes, SCREEN_WIDTH * SCREEN_HEIGHT of them.
+    // Bits 0-5 are the color and bits 6-8 the emphasis, as in PPUMASK.
+    pub fn frame(&self) -> &[u16] {
+        &self.frame
+    }
+
+    pub fn palette(&self) -> &[u32] {
+        &self.palette
+    }
+
+    // Conversions of the frame to the usual pixel formats, bytes in that order.
+    // `out` has to hold SCREEN_WIDTH * SCREEN_HEIGHT pixels.
+    pub fn frame_rgb24(&self, out: &mut [u8]) {
+        for (pixel, &index) in out.chunks_mut(3).zip(self.frame.iter()) {
+            let color = self.palette[index as usize];
+            pixel[0] = (color >> 16) as u8;
+            pixel[1] = (color >> 8) as u8;
+            pixel[2] = color as u8;
+        }
+    }
+
+    pub fn frame_rgba32(&self, out: &mut [u8]) {
+        for (pixel, &index) in out.chunks_mut(4).zip(self.frame.iter()) {
+            let color = self.palette[index as usize];
+            pixel[0] = (color >> 16) as u8;
+            pixel[1] = (color >> 8) as u8;
+            pixel[2] = color as u8;
+            pixel[3] = 0xFF;
+        }
+    }
+
+    pub fn frame_bgra32(&self, out: &mut [u8]) {
+        for (pixel, &index) in out.chunks_mut(4).zip(self.frame.iter()) {
+            let color = self.palette[index as usize];
+            pixel[0] = color as u8;
+            pixel[1] = (color >> 8) as u8;
+            pixel[2] = (color >> 16) as u8;
+            pixel[3] = 0xFF;
+        }
+    }
+
+    pub fn save_state(&self, writer: &mut dyn Write) -> io::Result<()> {
+        write_u8(writer, self.regs.control)?;
+        write_u8(writer, self.regs.mask)?;
+        write_u8(writer, self.regs.status)?;
+        write_u8(writer, self.regs.oam_address)?;
+        write_u8(writer, self.regs.oam_data)?;
+
+        write_u16(writer, self.v)?;
+        write_u16(writer, self.t)?;
+        write_u8(writer, self.x)?;
+        write_bool(writer, self.w)?;
+        write_u8(writer, self.data_buffer)?;
+
+        write_u64(writer, self.cycle)?;
+        write_u16(writer, self.scanline)?;
+        write_bool(writer, self.odd_frame)?;
+        write_u64(writer, self.dots)?;
+        write_bool(writer, self.nmi_line)?;
+        write_bool(writer, self.nmi_pending)?;
+
+        write_u8(writer, self.name_table_byte)?;
+        write_u8(writer, self.attribute_byte)?;
+        write_u8(writer, self.pattern_low)?;
+        write_u8(writer, self.pattern_high)?;
+        write_u16(writer, self.pattern_shift_low)?;
+        write_u16(writer, self.pattern_shift_high)?;
+        write_u16(writer, self.attribute_shift_low)?;
+        write_u16(writer, self.attribute_shift_high)?;
+
+        write_bytes(writer, &self.secondary_oam)?;
+        write_u8(writer, self.secondary_count as u8)?;
+        write_bool(writer, self.sprite_zero_next)?;
+
+        write_u8(writer, self.sprite_count as u8)?;
+        write_bool(writer, self.sprite_zero_line)?;
+        for pattern in self.sprite_patterns.iter() {
+            write_u32(writer, *pattern)?;
+        }
+        write_bytes(writer, &self.sprite_positions)?;
+        for priority in self.sprite_priorities.iter() {
+            write_bool(writer, *priority)?;
+        }
+
+        write_bytes(writer, &self.palettes)?;
+        write_bytes(writer, &self.name_tables)?;
+        write_bytes(writer, &self.oam_data)
+    }
+
+    pub fn load_state(&mut self, reader: &mut dyn Read) -> io::Result<()> {
+        self.regs.control = read_u8(reader)?;
+        self.regs.mask = read_u8(reader)?;
+        self.regs.status = read_u8(reader)?;
+        self.regs.oam_address = read_u8(reader)?;
+        self.regs.oam_data = read_u8(reader)?;
+
+        self.v = read_u16(reader)?;
+        self.t = read_u16(reader)?;
+        self.x = read_u8(reader)?;
+        self.w = read_bool(reader)?;
+        self.data_buffer = read_u8(reader)?;
+
+        self.cycle = read_u64(reader)?;
+        self.scanline = read_u16(reader)?;
+        self.odd_frame = read_bool(reader)?;
+        self.dots = read_u64(reader)?;
+        self.nmi_line = read_bool(reader)?;
+        self.nmi_pending = read_bool(reader)?;
+
+        self.name_table_byte = read_u8(reader)?;
+        self.attribute_byte = read_u8(reader)?;
+        self.pattern_low = read_u8(reader)?;
+        self.pattern_high = read_u8(reader)?;
+        self.pattern_shift_low = read_u16(reader)?;
+        self.pattern_shift_high = read_u16(reader)?;
+        self.attribute_shift_low = read_u16(reader)?;
+        self.attribute_shift_high = read_u16(reader)?;
+
+        read_bytes(reader, &mut self.secondary_oam)?;
+        self.secondary_count = std::cmp::min(read_u8(reader)? as usize, 8);
+        self.sprite_zero_next = read_bool(reader)?;
+
+        self.sprite_count = std::cmp::min(read_u8(reader)? as usize, 8);
+        self.sprite_zero_line = read_bool(reader)?;
+        for pattern in self.sprite_patterns.iter_mut() {
+            *pattern = read_u32(reader)?;
+        }
+        read_bytes(reader, &mut self.sprite_positions)?;
+        for priority in self.sprite_priorities.iter_mut() {
+            *priority = read_bool(reader)?;
+        }
+
+        read_bytes(reader, &mut self.palettes)?;
+        read_bytes(reader, &mut self.name_tables)?;
+        read_bytes(reader, &mut self.oam_data)
     }
 
     pub fn vram_load(&mut self, address: u16) -> u8 {
+        if address < 0x3F00 {
+            self.cartridge.mapper.borrow_mut().ppu_address(address, self.dots);
+        }
+
         if address < 0x2000 {
-            self.cartridge.chr[address as usize]
+            self.cartridge.mapper.borrow_mut().chr_load(address)
         } else if address < 0x3F00 {
-            self.name_tables[address as usize & 0x07FF]
+            let index = self.name_table_index(address);
+            self.name_tables[index]
         } else if address < 0x4000 {
-            self.palettes[address as usize & 0x1F]
+            self.palettes[palette_index(address)]
         } else {
             panic!("Reading VRam at 0x{:04x} is not valid!");
         }
     }
 
     pub fn vram_store(&mut self, address: u16, value: u8) {
+        if address < 0x3F00 {
+            self.cartridge.mapper.borrow_mut().ppu_address(address, self.dots);
+        }
+
         if address < 0x2000 {
-            // Can you even write to CHR...?
+            self.cartridge.mapper.borrow_mut().chr_store(address, value);
         } else if address < 0x3F00 {
-            self.name_tables[address as usize & 0x07FF] = value;
+            let index = self.name_table_index(address);
+            self.name_tables[index] = value;
         } else if address < 0x4000 {
-            self.palettes[address as usize & 0x1F] = value;
+            self.palettes[palette_index(address)] = value;
         } else {
             panic!("Storing value 0x{:02x} in VRam at 0x{:04x} is not valid!", value, address);
         }
@@ -202,7 +421,7 @@ impl Ppu {
     pub fn store(&mut self, address: u16, value: u8) {
         match address {
             // TODO: Handle NMIs when writing flags and all
-            0x2000 => { self.regs.control = value }
+            0x2000 => self.write_control(value),
             0x2001 => { self.regs.mask = value }
             0x2003 => { self.regs.oam_address = value }
             0x2004 => self.write_oam_data(value),
@@ -221,10 +440,18 @@ impl Ppu {
         }
     }
 
+    // $2000 Write to PPUCTRL
+    fn write_control(&mut self, value: u8) {
+        self.regs.control = value;
+        // t: ...GH.. ........ <- d: ......GH
+        self.t = (self.t & 0xF3FF) | ((value as u16 & 0x03) << 10);
+    }
+
     // $2002 Read from PPUSTATUS
     fn read_status(&mut self) -> u8 {
         let status = self.regs.status;
-        self.regs.status ^= 0x80; // Clear VBlank bit
+        self.regs.status &= !0x80; // Clear VBlank bit
+        self.w = false;
         status
     }
 
@@ -238,36 +465,42 @@ impl Ppu {
     fn write_oam_data(&mut self, value: u8) {
         let address = self.regs.oam_address as u16;
         self.oam_data[address as usize] = value;
-        self.regs.oam_address.wrapping_add(1);
+        self.regs.oam_address = self.regs.oam_address.wrapping_add(1);
     }
 
     // $2005 Write to PPUSCROLL
     fn write_scroll(&mut self, value: u8) {
-        if self.next_scroll_x {
-            self.scroll_x = value;
-            self.next_scroll_x = false;
+        let value = value as u16;
+        if !self.w {
+            // t: ....... ...HGFED <- d: HGFED...
+            // x:              CBA <- d: .....CBA
+            self.t = (self.t & 0xFFE0) | (value >> 3);
+            self.x = (value & 0x07) as u8;
         } else {
-            self.scroll_y = value;
-            self.next_scroll_x = true;
+            // t: CBA..HG FED..... <- d: HGFEDCBA
+            self.t = (self.t & 0x8C1F) | ((value & 0x07) << 12) | ((value & 0xF8) << 2);
         }
+        self.w = !self.w;
     }
 
     // $2006 Write to PPUADDR
-    fn write_address(&mut self, address: u8) {
-        if self.vram_rw_high {
-            self.regs.address = (self.regs.address & 0xFF) | ((address as u16) << 8);
-            self.vram_rw_high = false;
+    fn write_address(&mut self, value: u8) {
+        let value = value as u16;
+        if !self.w {
+            // t: .FEDCBA ........ <- d: ..FEDCBA, bit 14 is cleared
+            self.t = (self.t & 0x00FF) | ((value & 0x3F) << 8);
         } else {
-            self.regs.address = (self.regs.address & 0xFF00) | (address as u16);
-            self.vram_rw_high = true;
+            self.t = (self.t & 0xFF00) | value;
+            self.v = self.t;
         }
+        self.w = !self.w;
     }
 
     // $2007 Read from PPUDATA
     fn read_data(&mut self) -> u8 {
-        let address = self.regs.address;
+        let address = self.v & 0x3FFF;
         let value = self.vram_load(address);
-        self.regs.address += self.address_increment();
+        self.v = (self.v + self.address_increment()) & 0x7FFF;
 
         // http://wiki.nesdev.com/w/index.php/PPU_registers#Data_.28.242007.29_.3C.3E_read.2Fwrite
         if address < 0x3F00 {
@@ -281,9 +514,40 @@ impl Ppu {
 
     // $2007 Write to PPUDATA
     fn write_data(&mut self, value: u8) {
-        let address = self.regs.address as u16;
-        self.vram_store(address & 0x3FFF, value);
-        self.regs.address += self.address_increment();
+        let address = self.v & 0x3FFF;
+        self.vram_store(address, value);
+        self.v = (self.v + self.address_increment()) & 0x7FFF;
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_scrolling#Wrapping_around
+    // Moves v down one pixel, wrapping into the next nametable after row 29
+    fn increment_y(&mut self) {
+        if self.v & 0x7000 != 0x7000 {
+            self.v += 0x1000; // Fine Y
+            return;
+        }
+
+        self.v &= !0x7000;
+        let mut coarse_y = (self.v & 0x03E0) >> 5;
+        if coarse_y == 29 {
+            coarse_y = 0;
+            self.v ^= 0x0800; // Switch vertical nametable
+        } else if coarse_y == 31 {
+            coarse_y = 0; // Attribute rows, no nametable switch
+        } else {
+            coarse_y += 1;
+        }
+        self.v = (self.v & !0x03E0) | (coarse_y << 5);
+    }
+
+    // v: ....F.. ...EDCBA <- t: ....F.. ...EDCBA
+    fn copy_horizontal(&mut self) {
+        self.v = (self.v & !0x041F) | (self.t & 0x041F);
+    }
+
+    // v: IHGF.ED CBA..... <- t: IHGF.ED CBA.....
+    fn copy_vertical(&mut self) {
+        self.v = (self.v & !0x7BE0) | (self.t & 0x7BE0);
     }
 
     // Rendering
@@ -301,8 +565,8 @@ impl Ppu {
         }
     }
 
-    fn show_sprites(&self) -> bool { self.regs.mask & 0x08 != 0 }
-    fn show_background(&self) -> bool { self.regs.mask & 0x10 != 0 }
+    fn show_background(&self) -> bool { self.regs.mask & 0x08 != 0 }
+    fn show_sprites(&self) -> bool { self.regs.mask & 0x10 != 0 }
 
     fn sprite_size(&self) -> u8 {
         match self.regs.control & 0x20 {
@@ -311,163 +575,382 @@ impl Ppu {
         }
     }
 
-    fn get_pixel(&mut self, x: u8, offset: u16) -> u8 {
-        let p0 = self.vram_load(offset);
-        let p1 = self.vram_load(offset + 8);
-        let bit0 = (p0 >> (7 - ((x % 8) as u8))) & 1;
-        let bit1 = (p1 >> (7 - ((x % 8) as u8))) & 1;
-        (bit1 << 1) | bit0
+
+    fn rendering_enabled(&self) -> bool {
+        self.show_background() || self.show_sprites()
     }
 
-    fn get_background_pixel(&mut self, x: u8) -> u32 {
-        let x_offset = x as u16 / 8;
-        let y_offset = self.scanline as u16 / 8;
-        let x2 = x % 8;
-        let y2 = (self.scanline % 8) as u8;
+    // Whether the NMI handler should run, cleared on read
+    pub fn take_nmi(&mut self) -> bool {
+        let nmi = self.nmi_pending;
+        self.nmi_pending = false;
+        nmi
+    }
 
-        let tile_address = 0x2000 + 32 * y_offset + x_offset;
-        let tile = self.vram_load(tile_address) as u16;
+    // Runs the PPU alongside the CPU, 3 dots per CPU cycle
+    pub fn step(&mut self, cpu_cycles: u64) {
+        for _ in 0..cpu_cycles * 3 {
+            self.tick();
+        }
+    }
 
-        let mut offset = (tile << 4) + y2 as u16;
-        offset += self.background_pattern_table_address();
-        let pixel = self.get_pixel(x2, offset);
+    // http://wiki.nesdev.com/w/index.php/PPU_rendering
+    // http://wiki.nesdev.com/w/images/d/d1/Ntsc_timing.png
+    fn tick(&mut self) {
+        let visible = self.scanline < 240;
+        let pre_render = self.scanline == 261;
 
-        let block = y_offset / 4 * 8 + x_offset / 4;
-        let attributes = self.vram_load(0x23C0 + block);
-        let left = x_offset % 4 < 2;
-        let top = y_offset % 4 < 2;
+        if self.rendering_enabled() && (visible || pre_render) {
+            self.fetch_background();
+            self.fetch_sprites();
+        }
+
+        if visible && (1..=256).contains(&self.cycle) {
+            self.render_pixel();
+        }
 
-        let mut attribute_color = attributes;
-        if !left && top {
-            attribute_color = attributes >> 2;
-        } else if left && !top {
-            attribute_color = attributes >> 4;
-        } else if !left && !top {
-            attribute_color = attributes >> 6;
+        if self.scanline == 241 && self.cycle == 1 {
+            self.regs.status |= 0x80;
+            self.new_frame = true;
+            self.frames += 1;
+        } else if pre_render && self.cycle == 1 {
+            self.regs.status &= !0xE0; // VBlank, sprite 0 hit and sprite overflow
         }
 
-        attribute_color &= 0x3;
+        let nmi_line = self.regs.status & 0x80 != 0 && self.regs.control & 0x80 != 0;
+        if nmi_line && !self.nmi_line {
+            self.nmi_pending = true;
+        }
+        self.nmi_line = nmi_line;
 
-        let color = (attribute_color << 2) | pixel;
-        let palette_address = 0x3F00 + color as u16;
-        let palette = self.vram_load(palette_address) & 0x3F;
-        PALETTE_RGB[palette as usize]
+        self.advance();
     }
 
-    fn get_sprite_pixel(&mut self, x: u8, _background: bool) -> Option<u32> {
-        let mut visible_count = 0;
+    fn advance(&mut self) {
+        self.dots += 1;
 
-        for n in 0..64 {
-            if visible_count >= 8 { break; } // Not sure if that's correct...
+        // The pre-render line is one dot shorter on odd frames when rendering
+        if self.scanline == 261 && self.cycle == 339 && self.odd_frame && self.rendering_enabled() {
+            self.cycle = 340;
+        }
 
-            let sprite = Sprite {
-                y: self.oam_data[n * 4],
-                index: self.oam_data[n * 4 + 1],
-                attributes: self.oam_data[n * 4 + 2],
-                x: self.oam_data[n * 4 + 3],
-            };
+        self.cycle += 1;
+        if self.cycle > 340 {
+            self.cycle = 0;
+            self.scanline += 1;
+            if self.scanline > 261 {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
 
-            // FIXME: This doesn't really work for 8x16 sprites
-            let size = self.sprite_size();
-            // let on_scanline = (sprite.y as u16) < self.scanline && self.scanline < sprite.y as u16 + size as u16;
-            let in_box = x >= sprite.x && (x as u16) < sprite.x as u16 + size as u16;
-            let on_scanline = !(self.scanline < sprite.y as u16) && (self.scanline < sprite.y as u16 + 8);
-
-            if in_box && on_scanline {
-                visible_count += 1;
-
-                let pixel;
-                match sprite.get_tiles(self) {
-                    Tiles::Tiles8(tile) | Tiles::Tiles16(tile, _) => {
-                        let mut sprite_x = x - sprite.x;
-                        let mut sprite_y = self.scanline as u8 - sprite.y;
-
-                        if sprite.horizontal_flip() { sprite_x = 7 - sprite_x; }
-                        if sprite.vertical_flip() { sprite_y = 7 - sprite_y; }
-
-                        let mut offset = (tile << 4) + sprite_y as u16;
-                        offset += self.sprite_pattern_table_address();
-                        pixel = self.get_pixel(sprite_x, offset);
-                    }
-                    // _ => {}
-                    // Tiles::Tiles16(_top, _bottom) => { }
-                }
+    fn fetch_background(&mut self) {
+        let dot = self.cycle;
 
-                if pixel == 0 { continue }; // transparent, let's not do anything
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.shift_background();
 
-                // if visible_count == 0 && background {
-                //     self.regs.status |= 0x40; // set sprite 0 hit
-                // }
-                // visible_count += 1;
+            match (dot - 1) % 8 {
+                0 => {
+                    self.reload_background_shifters();
+                    let address = 0x2000 | (self.v & 0x0FFF);
+                    self.name_table_byte = self.vram_load(address);
+                }
+                2 => {
+                    let v = self.v;
+                    let address = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.attribute_byte = (self.vram_load(address) >> shift) & 0x3;
+                }
+                4 => {
+                    let address = self.background_tile_address();
+                    self.pattern_low = self.vram_load(address);
+                }
+                6 => {
+                    let address = self.background_tile_address() + 8;
+                    self.pattern_high = self.vram_load(address);
+                }
+                7 => self.increment_x(),
+                _ => ()
+            }
+        }
 
-                let color = (sprite.palette() << 2) | pixel;
-                let palette_address = 0x3F00 + color as u16;
-                let palette = self.vram_load(palette_address) & 0x3F;
-                return Some(PALETTE_RGB[palette as usize]);
+        match dot {
+            256 => self.increment_y(),
+            257 => self.copy_horizontal(),
+            // Unused nametable fetches, MMC5 relies on them
+            338 | 340 => {
+                let address = 0x2000 | (self.v & 0x0FFF);
+                self.name_table_byte = self.vram_load(address);
             }
+            280..=304 if self.scanline == 261 => self.copy_vertical(),
+            _ => ()
         }
+    }
 
-        None
+    fn background_tile_address(&self) -> u16 {
+        let fine_y = (self.v >> 12) & 0x07;
+        self.background_pattern_table_address() + ((self.name_table_byte as u16) << 4) + fine_y
     }
 
-    fn set_pixel(&mut self, x: u32, y: u32, color: u32) {
-        self.frame_content[((y * 256 + x) * 3 + 2) as usize] = (color >> 16) as u8;
-        self.frame_content[((y * 256 + x) * 3 + 1) as usize] = (color >> 8) as u8;
-        self.frame_content[((y * 256 + x) * 3 + 0) as usize] = color as u8;
+    fn shift_background(&mut self) {
+        self.pattern_shift_low <<= 1;
+        self.pattern_shift_high <<= 1;
+        self.attribute_shift_low <<= 1;
+        self.attribute_shift_high <<= 1;
     }
 
-    fn make_scanline(&mut self) {
-        let scanline = self.scanline;
+    // The next tile goes in the low byte, the high byte is the one being drawn
+    fn reload_background_shifters(&mut self) {
+        self.pattern_shift_low = (self.pattern_shift_low & 0xFF00) | self.pattern_low as u16;
+        self.pattern_shift_high = (self.pattern_shift_high & 0xFF00) | self.pattern_high as u16;
 
-        for x in 0..255 {
-            let mut background = false;
-            if self.show_background() {
-                let c = self.get_background_pixel(x);
-                background = true;
-                self.set_pixel(x as u32, scanline as u32, c);
-            } else {
-                self.set_pixel(x as u32, scanline as u32, 0);
+        let low = if self.attribute_byte & 1 != 0 { 0xFF } else { 0x00 };
+        let high = if self.attribute_byte & 2 != 0 { 0xFF } else { 0x00 };
+        self.attribute_shift_low = (self.attribute_shift_low & 0xFF00) | low;
+        self.attribute_shift_high = (self.attribute_shift_high & 0xFF00) | high;
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_scrolling#Coarse_X_increment
+    fn increment_x(&mut self) {
+        if self.v & 0x001F == 31 {
+            self.v &= !0x001F;
+            self.v ^= 0x0400; // Switch horizontal nametable
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // Sprites for the next line are picked from OAM into secondary OAM, then their
+    // patterns are fetched during dots 257-320, 8 dots per sprite. Unused slots fetch tile $FF.
+    // http://wiki.nesdev.com/w/index.php/PPU_sprite_evaluation
+    fn fetch_sprites(&mut self) {
+        let dot = self.cycle;
+        let visible = self.scanline < 240;
+
+        match dot {
+            // Secondary OAM is cleared during dots 1-64 and filled during 65-256,
+            // both are done at once here.
+            64 if visible => self.secondary_oam = [0xFF; 32],
+            256 if visible => self.evaluate_sprites(),
+            // The pre-render line fetches sprites too, but none are drawn on line 0
+            257 => {
+                self.sprite_count = if visible { self.secondary_count } else { 0 };
+                self.sprite_zero_line = visible && self.sprite_zero_next;
             }
+            _ => ()
+        }
 
-            if self.show_sprites() {
-                match self.get_sprite_pixel(x, background) {
-                    Some(color) => self.set_pixel(x as u32, scanline as u32, color),
-                    _ => ()
+        if !(257..=320).contains(&dot) {
+            return;
+        }
+        self.regs.oam_address = 0;
+
+        let slot = (dot - 257) as usize / 8;
+        match (dot - 257) % 8 {
+            // Garbage nametable fetches
+            0 | 2 => {
+                let address = 0x2000 | (self.v & 0x0FFF);
+                self.vram_load(address);
+            }
+            4 => {
+                let address = self.sprite_tile_address(slot);
+                self.pattern_low = self.vram_load(address);
+            }
+            6 => {
+                let address = self.sprite_tile_address(slot) + 8;
+                self.pattern_high = self.vram_load(address);
+                if slot < self.sprite_count {
+                    let sprite = self.secondary_sprite(slot);
+                    self.sprite_positions[slot] = sprite.x;
+                    self.sprite_priorities[slot] = sprite.behind_background();
+                    self.sprite_patterns[slot] = self.sprite_pattern(slot);
                 }
             }
+            _ => ()
         }
     }
 
-    pub fn step(&mut self, cpu_cycle: u64) -> PpuResult {
-        let mut result = PpuResult { new_frame: false, nmi: false };
+    fn evaluate_sprites(&mut self) {
+        let scanline = self.scanline;
+        let height = self.sprite_size() as u16;
+        let in_range = |y: u8| scanline >= y as u16 && scanline < y as u16 + height;
+
+        self.secondary_count = 0;
+        self.sprite_zero_next = false;
+
+        // The Y coordinate is always copied, the rest only when the sprite is in range
+        let mut n = 0;
+        while n < 64 && self.secondary_count < 8 {
+            let y = self.oam_data[n * 4];
+            let slot = self.secondary_count * 4;
+            self.secondary_oam[slot] = y;
+
+            if in_range(y) {
+                self.secondary_oam[slot + 1..slot + 4].copy_from_slice(&self.oam_data[n * 4 + 1..n * 4 + 4]);
+                if n == 0 {
+                    self.sprite_zero_next = true;
+                }
+                self.secondary_count += 1;
+            }
+            n += 1;
+        }
 
-        loop {
-            let next_scanline = 114 + self.cycle;
-            if next_scanline > cpu_cycle {
+        // Once 8 sprites are found the PPU looks for a 9th one to set the overflow flag,
+        // but it increments the byte index along with the sprite index on every miss, so
+        // it ends up checking tile numbers, attributes and X positions as Y coordinates.
+        let mut m = 0;
+        while n < 64 {
+            if in_range(self.oam_data[n * 4 + m]) {
+                self.regs.status |= 0x20;
                 break;
             }
-            if self.scanline < 240 {
-                self.make_scanline();
+            n += 1;
+            m = (m + 1) % 4;
+        }
+    }
+
+    fn secondary_sprite(&self, slot: usize) -> Sprite {
+        let n = slot * 4;
+        Sprite {
+            y: self.secondary_oam[n],
+            index: self.secondary_oam[n + 1],
+            attributes: self.secondary_oam[n + 2],
+            x: self.secondary_oam[n + 3],
+        }
+    }
+
+    fn sprite_tile_address(&self, slot: usize) -> u16 {
+        if slot >= self.sprite_count {
+            return match self.sprite_size() {
+                8 => self.sprite_pattern_table_address() + 0xFF0,
+                _ => 0x1FF0,
+            };
+        }
+
+        let sprite = self.secondary_sprite(slot);
+        let height = self.sprite_size() as u16;
+        let mut row = self.scanline - sprite.y as u16;
+        // Flipping 8x16 sprites also swaps the top and bottom tiles
+        if sprite.vertical_flip() { row = height - 1 - row; }
+
+        match sprite.get_tiles(self) {
+            Tiles::Tiles8(tile) => tile + row,
+            Tiles::Tiles16(top, _) if row < 8 => top + row,
+            Tiles::Tiles16(_, bottom) => bottom + row - 8,
+        }
+    }
+
+    // Packs the fetched pattern bytes into 8 nibbles, leftmost pixel first
+    fn sprite_pattern(&self, slot: usize) -> u32 {
+        let sprite = self.secondary_sprite(slot);
+        let palette = (sprite.palette() & 0x3) as u32; // The upper palettes are implied
+
+        let mut pattern = 0;
+        for i in 0..8 {
+            let bit = if sprite.horizontal_flip() { i } else { 7 - i };
+            let low = (self.pattern_low >> bit) as u32 & 1;
+            let high = (self.pattern_high >> bit) as u32 & 1;
+            pattern = (pattern << 4) | (palette << 2) | (high << 1) | low;
+        }
+        pattern
+    }
+
+    // 4 bits: palette and color
+    fn background_pixel(&self) -> u8 {
+        let bit = 15 - self.x as u16;
+        let low = (self.pattern_shift_low >> bit) & 1;
+        let high = (self.pattern_shift_high >> bit) & 1;
+        let attribute_low = (self.attribute_shift_low >> bit) & 1;
+        let attribute_high = (self.attribute_shift_high >> bit) & 1;
+        ((attribute_high << 3) | (attribute_low << 2) | (high << 1) | low) as u8
+    }
+
+    // Slot and 4 bits of palette and color, from the first opaque sprite at x
+    fn sprite_pixel(&self, x: u8) -> Option<(usize, u8)> {
+        for i in 0..self.sprite_count {
+            let position = self.sprite_positions[i];
+            if x < position || x - position >= 8 {
+                continue;
+            }
+            let offset = x - position;
+
+            let color = (self.sprite_patterns[i] >> ((7 - offset) * 4)) as u8 & 0x0F;
+            if color & 0x03 != 0 {
+                return Some((i, color));
             }
-            self.scanline += 1;
+        }
 
-            if self.scanline == 241 { // VBlank
-                self.regs.status |= 0x80;
-                self.regs.control &= !0x40; // sprite zero hit
-                if (self.regs.control | 0x80) != 0 {
-                    result.nmi = true;
-                }
-            } else if self.scanline == 261 {
-                result.new_frame = true;
-                self.frames += 1;
-                self.scanline = 0;
-                self.regs.status &= !0x80;
+        None
+    }
+
+    // PPUMASK bit 0 keeps only the gray column, bits 5-7 pick the emphasized colors
+    // http://wiki.nesdev.com/w/index.php/PPU_registers#Color_control
+    fn palette_color(&self, index: u8) -> u16 {
+        let mut color = self.palettes[palette_index(index as u16)] & 0x3F;
+        if self.regs.mask & 0x01 != 0 {
+            color &= 0x30;
+        }
+        let emphasis = (self.regs.mask >> 5) as u16;
+        emphasis << 6 | color as u16
+    }
+
+    fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        self.frame[y * SCREEN_WIDTH + x] = color;
+    }
+
+    // Left edge clipping, PPUMASK bits 1 and 2
+    fn show_background_at(&self, x: u8) -> bool {
+        self.show_background() && (x >= 8 || self.regs.mask & 0x02 != 0)
+    }
+
+    fn show_sprites_at(&self, x: u8) -> bool {
+        self.show_sprites() && (x >= 8 || self.regs.mask & 0x04 != 0)
+    }
+
+    // http://wiki.nesdev.com/w/index.php/PPU_rendering#Preface
+    fn render_pixel(&mut self) {
+        let x = (self.cycle - 1) as u8;
+
+        let mut background = 0;
+        if self.show_background_at(x) {
+            background = self.background_pixel();
+        }
+        let opaque_background = background & 0x03 != 0;
+
+        // Transparent pixels show the backdrop color at $3F00
+        let mut color = if opaque_background { background } else { 0 };
+
+        let sprite = if self.show_sprites_at(x) { self.sprite_pixel(x) } else { None };
+        if let Some((slot, pixel)) = sprite {
+            // http://wiki.nesdev.com/w/index.php/PPU_OAM#Sprite_zero_hits
+            if slot == 0 && self.sprite_zero_line && opaque_background && x != 255 {
+                self.regs.status |= 0x40;
             }
 
-            self.cycle += 114;
+            // Only the frontmost opaque sprite is considered, even if it is behind the
+            // background and another one isn't.
+            // http://wiki.nesdev.com/w/index.php/PPU_sprite_priority
+            if !opaque_background || !self.sprite_priorities[slot] {
+                color = 0x10 | pixel;
+            }
         }
 
-        self.cycle = 0;
-        result
+        let color = self.palette_color(color);
+        let y = self.scanline as usize;
+        self.set_pixel(x as usize, y, color);
     }
 }
+
+// $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C
+// http://wiki.nesdev.com/w/index.php/PPU_palettes#Memory_Map
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1F;
+    if index >= 0x10 && index & 3 == 0 {
+        index - 0x10
+    } else {
+        index
+    }
+}
+
@@ -0,0 +1,161 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::io::prelude::*;
+
+use cpu::Cpu;
+
+// Save state layout (all integers are little endian):
+//
+// "SENS"            magic number
+// u32               format version
+// u16, u64, u64     mapper number, PRG and CHR ROM sizes of the cartridge
+// ...               CPU, RAM, PPU, controller, APU and mapper state, in that order
+//
+// Bump VERSION whenever the layout of any component changes.
+
+const MAGIC: &[u8; 4] = b"SENS";
+pub const VERSION: u32 = 8;
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    BadMagic,
+    UnsupportedVersion(u32),
+    WrongCartridge,
+    Io(io::Error),
+}
+
+impl fmt::Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SaveStateError::BadMagic => write!(f, "not a save state"),
+            SaveStateError::UnsupportedVersion(v) =>
+                write!(f, "unsupported save state version {} (expected {})", v, VERSION),
+            SaveStateError::WrongCartridge => write!(f, "save state was made with a different cartridge"),
+            SaveStateError::Io(ref e) => write!(f, "I/O error: {}", e),
+        }
+    }
+}
+
+impl Error for SaveStateError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SaveStateError::Io(ref e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> SaveStateError {
+        SaveStateError::Io(e)
+    }
+}
+
+pub fn save<W: Write>(cpu: &Cpu, writer: &mut W) -> Result<(), SaveStateError> {
+    let header = &cpu.ram.cartridge.header;
+
+    writer.write_all(MAGIC)?;
+    write_u32(writer, VERSION)?;
+    write_u16(writer, header.mapper())?;
+    write_u64(writer, header.prg_rom_size as u64)?;
+    write_u64(writer, header.chr_rom_size as u64)?;
+
+    cpu.save_state(writer)?;
+    Ok(())
+}
+
+// The machine is left untouched if the header doesn't match, but a truncated
+// or corrupted state can leave it half restored.
+pub fn load<R: Read>(cpu: &mut Cpu, reader: &mut R) -> Result<(), SaveStateError> {
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic != MAGIC {
+        return Err(SaveStateError::BadMagic);
+    }
+
+    let version = read_u32(reader)?;
+    if version != VERSION {
+        return Err(SaveStateError::UnsupportedVersion(version));
+    }
+
+    let mapper = read_u16(reader)?;
+    let prg_rom_size = read_u64(reader)?;
+    let chr_rom_size = read_u64(reader)?;
+    {
+        let header = &cpu.ram.cartridge.header;
+        if mapper != header.mapper()
+            || prg_rom_size != header.prg_rom_size as u64
+            || chr_rom_size != header.chr_rom_size as u64 {
+            return Err(SaveStateError::WrongCartridge);
+        }
+    }
+
+    cpu.load_state(reader)?;
+    Ok(())
+}
+
+// Helpers used by every component to (de)serialize its fields
+
+pub fn write_u8(writer: &mut dyn Write, value: u8) -> io::Result<()> {
+    writer.write_all(&[value])
+}
+
+pub fn write_bool(writer: &mut dyn Write, value: bool) -> io::Result<()> {
+    write_u8(writer, value as u8)
+}
+
+pub fn write_u16(writer: &mut dyn Write, value: u16) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u32(writer: &mut dyn Write, value: u32) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+pub fn write_u64(writer: &mut dyn Write, value: u64) -> io::Result<()> {
+    writer.write_all(&value.to_le_bytes())
+}
+
+// Length prefixed, so that loading into a buffer of a different size fails
+pub fn write_bytes(writer: &mut dyn Write, bytes: &[u8]) -> io::Result<()> {
+    write_u32(writer, bytes.len() as u32)?;
+    writer.write_all(bytes)
+}
+
+pub fn read_u8(reader: &mut dyn Read) -> io::Result<u8> {
+    let mut buffer = [0; 1];
+    reader.read_exact(&mut buffer)?;
+    Ok(buffer[0])
+}
+
+pub fn read_bool(reader: &mut dyn Read) -> io::Result<bool> {
+    Ok(read_u8(reader)? != 0)
+}
+
+pub fn read_u16(reader: &mut dyn Read) -> io::Result<u16> {
+    let mut buffer = [0; 2];
+    reader.read_exact(&mut buffer)?;
+    Ok(u16::from_le_bytes(buffer))
+}
+
+pub fn read_u32(reader: &mut dyn Read) -> io::Result<u32> {
+    let mut buffer = [0; 4];
+    reader.read_exact(&mut buffer)?;
+    Ok(u32::from_le_bytes(buffer))
+}
+
+pub fn read_u64(reader: &mut dyn Read) -> io::Result<u64> {
+    let mut buffer = [0; 8];
+    reader.read_exact(&mut buffer)?;
+    Ok(u64::from_le_bytes(buffer))
+}
+
+pub fn read_bytes(reader: &mut dyn Read, bytes: &mut [u8]) -> io::Result<()> {
+    let len = read_u32(reader)? as usize;
+    if len != bytes.len() {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+            format!("expected {} bytes, save state has {}", bytes.len(), len)));
+    }
+    reader.read_exact(bytes)
+}
@@ -0,0 +1,375 @@
+use ppu::SCREEN_WIDTH;
+
+// Pixel art scalers for the frontend, run on the CPU so the result doesn't depend
+// on how the video driver stretches textures.
+
+// NTSC pixels are a bit wider than they are tall
+// http://wiki.nesdev.com/w/index.php/Overscan#Pixel_aspect_ratio
+pub const PIXEL_ASPECT_RATIO: f64 = 8.0 / 7.0;
+
+// Width to show the screen at when it is `scale` times as tall, e.g. 585 at twice the size
+pub fn aspect_width(scale: usize) -> usize {
+    (SCREEN_WIDTH as f64 * scale as f64 * PIXEL_ASPECT_RATIO).round() as usize
+}
+
+// 0xRRGGBB pixels
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub pixels: Vec<u32>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize) -> Image {
+        Image { width, height, pixels: vec![0; width * height] }
+    }
+
+    // e.g. from Ppu::frame and Ppu::palette
+    pub fn from_indexed(frame: &[u16], palette: &[u32], width: usize, height: usize) -> Image {
+        let pixels = frame.iter().map(|&index| palette[index as usize]).collect();
+        Image { width, height, pixels }
+    }
+
+    pub fn from_rgb24(bytes: &[u8], width: usize, height: usize) -> Image {
+        let pixels = bytes.chunks(3).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32).collect();
+        Image { width, height, pixels }
+    }
+
+    pub fn to_rgb24(&self, out: &mut Vec<u8>) {
+        out.clear();
+        for &color in self.pixels.iter() {
+            out.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, color as u8]);
+        }
+    }
+
+    // Out of bounds coordinates are clamped to the edges
+    fn get(&self, x: isize, y: isize) -> u32 {
+        let x = x.max(0).min(self.width as isize - 1) as usize;
+        let y = y.max(0).min(self.height as isize - 1) as usize;
+        self.pixels[y * self.width + x]
+    }
+
+    fn set(&mut self, x: usize, y: usize, color: u32) {
+        self.pixels[y * self.width + x] = color;
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScaleFilter {
+    Nearest,
+    Scale2x,
+    Scale3x,
+    Hq2x,
+    Scanlines,
+}
+
+impl ScaleFilter {
+    pub fn parse(name: &str) -> Option<ScaleFilter> {
+        match name {
+            "nearest" => Some(ScaleFilter::Nearest),
+            "scale2x" => Some(ScaleFilter::Scale2x),
+            "scale3x" => Some(ScaleFilter::Scale3x),
+            "hq2x" => Some(ScaleFilter::Hq2x),
+            "scanlines" => Some(ScaleFilter::Scanlines),
+            _ => None,
+        }
+    }
+
+    // Factor of the pattern based filters, 1 for the others
+    fn pattern_factor(self) -> usize {
+        match self {
+            ScaleFilter::Scale2x | ScaleFilter::Hq2x => 2,
+            ScaleFilter::Scale3x => 3,
+            ScaleFilter::Nearest | ScaleFilter::Scanlines => 1,
+        }
+    }
+
+    // Size of the output relative to the input. The pattern based filters are
+    // followed by nearest neighbor up to the largest multiple of their own factor
+    // that fits in `scale`, the rest is up to resize().
+    pub fn factor(self, scale: usize) -> usize {
+        match self {
+            ScaleFilter::Nearest => scale.max(1),
+            ScaleFilter::Scanlines => scale.max(2),
+            _ => {
+                let pattern = self.pattern_factor();
+                pattern * (scale / pattern).max(1)
+            }
+        }
+    }
+
+    pub fn apply(self, image: &Image, scale: usize) -> Image {
+        let factor = self.factor(scale);
+        let scaled = match self {
+            ScaleFilter::Nearest => return nearest(image, factor),
+            ScaleFilter::Scanlines => return scanlines(image, factor),
+            ScaleFilter::Scale2x => scale2x(image),
+            ScaleFilter::Scale3x => scale3x(image),
+            ScaleFilter::Hq2x => hq2x(image),
+        };
+
+        match factor / self.pattern_factor() {
+            1 => scaled,
+            n => nearest(&scaled, n),
+        }
+    }
+}
+
+// Weighted average of colors, component by component
+fn blend(colors: &[(u32, u32)]) -> u32 {
+    let total: u32 = colors.iter().map(|&(_, weight)| weight).sum();
+    let mut result = 0;
+    for shift in [16, 8, 0].iter() {
+        let sum: u32 = colors.iter().map(|&(color, weight)| ((color >> shift) & 0xFF) * weight).sum();
+        result |= (sum / total) << shift;
+    }
+    result
+}
+
+// Source pixels covered by each of `out_len` pixels, with how much of them, when
+// stretching `len` pixels. Every source pixel is `out_len` units wide and every
+// output pixel `len` units.
+fn coverage(len: usize, out_len: usize) -> Vec<Vec<(usize, u32)>> {
+    (0..out_len).map(|i| {
+        let (start, end) = (i * len, (i + 1) * len);
+        (start / out_len..end.div_ceil(out_len)).map(|j| {
+            let overlap = end.min((j + 1) * out_len) - start.max(j * out_len);
+            (j, overlap as u32)
+        }).collect()
+    }).collect()
+}
+
+// Final non-integer step to the window size, e.g. the 8:7 stretch. Each output
+// pixel averages the area it covers, so when enlarging only the pixels straddling
+// a boundary get blended and every column stays the same width.
+pub fn resize(image: &Image, width: usize, height: usize) -> Image {
+    let mut colors = Vec::new();
+
+    let columns = coverage(image.width, width);
+    let mut wide = Image::new(width, image.height);
+    for y in 0..image.height {
+        let row = &image.pixels[y * image.width..(y + 1) * image.width];
+        for (x, weights) in columns.iter().enumerate() {
+            colors.clear();
+            colors.extend(weights.iter().map(|&(j, weight)| (row[j], weight)));
+            wide.set(x, y, blend(&colors));
+        }
+    }
+
+    let rows = coverage(image.height, height);
+    let mut out = Image::new(width, height);
+    for (y, weights) in rows.iter().enumerate() {
+        for x in 0..width {
+            colors.clear();
+            colors.extend(weights.iter().map(|&(j, weight)| (wide.pixels[j * width + x], weight)));
+            out.set(x, y, blend(&colors));
+        }
+    }
+    out
+}
+
+pub fn nearest(image: &Image, factor: usize) -> Image {
+    let mut out = Image::new(image.width * factor, image.height * factor);
+    for y in 0..out.height {
+        let row = &image.pixels[(y / factor) * image.width..(y / factor + 1) * image.width];
+        for x in 0..out.width {
+            out.pixels[y * out.width + x] = row[x / factor];
+        }
+    }
+    out
+}
+
+// Nearest neighbor, with the last line of each source line darkened
+pub fn scanlines(image: &Image, factor: usize) -> Image {
+    let mut out = nearest(image, factor);
+    for y in (factor - 1..out.height).step_by(factor) {
+        for pixel in out.pixels[y * out.width..(y + 1) * out.width].iter_mut() {
+            *pixel = (*pixel >> 1) & 0x7F7F7F;
+        }
+    }
+    out
+}
+
+// http://www.scale2x.it/algorithm
+//
+//   A B C
+//   D E F
+//   G H I
+pub fn scale2x(image: &Image) -> Image {
+    let mut out = Image::new(image.width * 2, image.height * 2);
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let b = image.get(xi, yi - 1);
+            let d = image.get(xi - 1, yi);
+            let e = image.get(xi, yi);
+            let f = image.get(xi + 1, yi);
+            let h = image.get(xi, yi + 1);
+
+            let mut block = [e; 4];
+            if b != h && d != f {
+                block[0] = if d == b { d } else { e };
+                block[1] = if b == f { f } else { e };
+                block[2] = if d == h { d } else { e };
+                block[3] = if h == f { f } else { e };
+            }
+
+            out.set(x * 2, y * 2, block[0]);
+            out.set(x * 2 + 1, y * 2, block[1]);
+            out.set(x * 2, y * 2 + 1, block[2]);
+            out.set(x * 2 + 1, y * 2 + 1, block[3]);
+        }
+    }
+
+    out
+}
+
+pub fn scale3x(image: &Image) -> Image {
+    let mut out = Image::new(image.width * 3, image.height * 3);
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let a = image.get(xi - 1, yi - 1);
+            let b = image.get(xi, yi - 1);
+            let c = image.get(xi + 1, yi - 1);
+            let d = image.get(xi - 1, yi);
+            let e = image.get(xi, yi);
+            let f = image.get(xi + 1, yi);
+            let g = image.get(xi - 1, yi + 1);
+            let h = image.get(xi, yi + 1);
+            let i = image.get(xi + 1, yi + 1);
+
+            let mut block = [e; 9];
+            if b != h && d != f {
+                block[0] = if d == b { d } else { e };
+                block[1] = if (d == b && e != c) || (b == f && e != a) { b } else { e };
+                block[2] = if b == f { f } else { e };
+                block[3] = if (d == b && e != g) || (d == h && e != a) { d } else { e };
+                block[5] = if (b == f && e != i) || (h == f && e != c) { f } else { e };
+                block[6] = if d == h { d } else { e };
+                block[7] = if (d == h && e != i) || (h == f && e != g) { h } else { e };
+                block[8] = if h == f { f } else { e };
+            }
+
+            for (n, &color) in block.iter().enumerate() {
+                out.set(x * 3 + n % 3, y * 3 + n / 3, color);
+            }
+        }
+    }
+
+    out
+}
+
+// hqx compares colors in YUV space, with these thresholds
+// https://en.wikipedia.org/wiki/Hqx
+const HQX_Y_THRESHOLD: i32 = 48;
+const HQX_U_THRESHOLD: i32 = 7;
+const HQX_V_THRESHOLD: i32 = 6;
+
+// The approximation of YUV the original hqx uses
+fn yuv(color: u32) -> (i32, i32, i32) {
+    let r = ((color >> 16) & 0xFF) as i32;
+    let g = ((color >> 8) & 0xFF) as i32;
+    let b = (color & 0xFF) as i32;
+    ((r + g + b) >> 2, 128 + ((r - b) >> 2), 128 + ((2 * g - r - b) >> 3))
+}
+
+fn different(a: u32, b: u32) -> bool {
+    let (ya, ua, va) = yuv(a);
+    let (yb, ub, vb) = yuv(b);
+    (ya - yb).abs() > HQX_Y_THRESHOLD
+        || (ua - ub).abs() > HQX_U_THRESHOLD
+        || (va - vb).abs() > HQX_V_THRESHOLD
+}
+
+// Top left output pixel of hq2x, for the 3x3 neighborhood `w` of a source pixel:
+//
+//   0 1 2
+//   3 4 5
+//   6 7 8
+//
+// `pattern` has a bit for every neighbor, in that order without the center, set
+// when it is different from the center. These rules are the 256 case table of
+// the original, boiled down to the top left pixel by FFmpeg's vf_hqx.
+fn hq2x_pixel(w: &[u32; 9], pattern: u8) -> u32 {
+    let any = |rules: &[(u8, u8)]| rules.iter().any(|&(mask, bits)| pattern & mask == bits);
+    let (w0, w1, w3, w4, w5, w7) = (w[0], w[1], w[3], w[4], w[5], w[7]);
+
+    if any(&[(0xBF, 0x37), (0xDB, 0x13)]) && different(w1, w5) {
+        blend(&[(w4, 3), (w3, 1)])
+    } else if any(&[(0xDB, 0x49), (0xEF, 0x6D)]) && different(w7, w3) {
+        blend(&[(w4, 3), (w1, 1)])
+    } else if any(&[(0x0B, 0x0B), (0xFE, 0x4A), (0xFE, 0x1A)]) && different(w3, w1) {
+        w4
+    } else if any(&[
+        (0x6F, 0x2A), (0x5B, 0x0A), (0xBF, 0x3A), (0xDF, 0x5A), (0x9F, 0x8A),
+        (0xCF, 0x8A), (0xEF, 0x4E), (0x3F, 0x0E), (0xFB, 0x5A), (0xBB, 0x8A),
+        (0x7F, 0x5A), (0xAF, 0x8A), (0xEB, 0x8A),
+    ]) && different(w3, w1) {
+        blend(&[(w4, 3), (w0, 1)])
+    } else if any(&[(0x0B, 0x08)]) {
+        blend(&[(w4, 2), (w0, 1), (w1, 1)])
+    } else if any(&[(0x0B, 0x02)]) {
+        blend(&[(w4, 2), (w0, 1), (w3, 1)])
+    } else if any(&[(0x2F, 0x2F)]) {
+        blend(&[(w4, 14), (w3, 1), (w1, 1)])
+    } else if any(&[(0xBF, 0x37), (0xDB, 0x13)]) {
+        blend(&[(w4, 5), (w1, 2), (w3, 1)])
+    } else if any(&[(0xDB, 0x49), (0xEF, 0x6D)]) {
+        blend(&[(w4, 5), (w3, 2), (w1, 1)])
+    } else if any(&[(0x1B, 0x03), (0x4F, 0x43), (0x8B, 0x83), (0x6B, 0x43)]) {
+        blend(&[(w4, 3), (w3, 1)])
+    } else if any(&[(0x4B, 0x09), (0x8B, 0x89), (0x1F, 0x19), (0x3B, 0x19)]) {
+        blend(&[(w4, 3), (w1, 1)])
+    } else if any(&[(0x7E, 0x2A), (0xEF, 0xAB), (0xBF, 0x8F), (0x7E, 0x0E)]) {
+        blend(&[(w4, 2), (w3, 3), (w1, 3)])
+    } else if any(&[(0xFB, 0x6A), (0x6F, 0x6E), (0x3F, 0x3E), (0xFB, 0xFA), (0xDF, 0xDE), (0xDF, 0x1E)]) {
+        blend(&[(w4, 3), (w0, 1)])
+    } else if any(&[
+        (0x0A, 0x00), (0x4F, 0x4B), (0x9F, 0x1B), (0x2F, 0x0B), (0xBE, 0x0A),
+        (0xEE, 0x0A), (0x7E, 0x0A), (0xEB, 0x4B), (0x3B, 0x1B),
+    ]) {
+        blend(&[(w4, 2), (w3, 1), (w1, 1)])
+    } else {
+        blend(&[(w4, 6), (w3, 1), (w1, 1)])
+    }
+}
+
+// http://web.archive.org/web/20131205091805/http://www.hiend3d.com/hq2x.html
+pub fn hq2x(image: &Image) -> Image {
+    let mut out = Image::new(image.width * 2, image.height * 2);
+
+    for y in 0..image.height {
+        for x in 0..image.width {
+            let (xi, yi) = (x as isize, y as isize);
+            let mut w = [0; 9];
+            for (i, pixel) in w.iter_mut().enumerate() {
+                *pixel = image.get(xi + (i % 3) as isize - 1, yi + (i / 3) as isize - 1);
+            }
+
+            // The other output pixels are the top left one of a mirrored neighborhood
+            for &(dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)].iter() {
+                let mut mirrored = [0; 9];
+                for (i, pixel) in mirrored.iter_mut().enumerate() {
+                    let (column, row) = (i % 3, i / 3);
+                    let column = if dx == 1 { 2 - column } else { column };
+                    let row = if dy == 1 { 2 - row } else { row };
+                    *pixel = w[row * 3 + column];
+                }
+
+                let mut pattern = 0;
+                for (bit, &i) in [0, 1, 2, 3, 5, 6, 7, 8].iter().enumerate() {
+                    if different(mirrored[i], mirrored[4]) {
+                        pattern |= 1 << bit;
+                    }
+                }
+
+                out.set(x * 2 + dx, y * 2 + dy, hq2x_pixel(&mirrored, pattern));
+            }
+        }
+    }
+
+    out
+}
@@ -1,22 +1,65 @@
 extern crate sen;
 
 #[cfg(test)]
-use std::fs::File;
-use std::path::Path;
+use std::cell::RefCell;
+use std::env;
+use std::fs;
+use std::io::Cursor;
+use std::rc::Rc;
+use sen::apu::{Apu, AudioSink, CPU_CLOCK_RATE};
+use sen::apu::dmc::Dmc;
+use sen::apu::noise::Noise;
+use sen::apu::resampler::Resampler;
+use sen::apu::triangle::Triangle;
+use sen::apu::wav::WavWriter;
 use sen::cpu::Cpu;
 use sen::ppu::Ppu;
-use sen::cartridge::Cartridge;
+use sen::cartridge::{Cartridge, CartridgeError, ConsoleType, Mirroring, NesHeader, Timing};
+use sen::controller::Controller;
+use sen::memory::CpuMemory;
+use sen::ntsc::{self, NtscFilter, NtscPreset};
+use sen::palette;
+use sen::savestate;
+use sen::scale::{self, Image, ScaleFilter};
+
+// Minimal iNES image: 16KB PRG, 8KB CHR
+fn make_rom(mapper: u8) -> Vec<u8> {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 1, mapper << 4, mapper & 0xF0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(vec![0; 0x4000 + 0x2000]);
+    rom
+}
+
+// Every 16KB PRG bank is filled with its number, every 4KB of CHR with 0x80 | its number
+fn make_banked_rom(mapper: u8, prg_banks: u8, chr_banks: u8) -> Vec<u8> {
+    let mut rom = make_rom(mapper);
+    rom.truncate(16);
+    rom[4] = prg_banks;
+    rom[5] = chr_banks;
+    for bank in 0..prg_banks {
+        rom.extend(vec![bank; 0x4000]);
+    }
+    for bank in 0..chr_banks * 2 {
+        rom.extend(vec![0x80 | bank; 0x1000]);
+    }
+    rom
+}
 
 fn make_cpu() -> Cpu {
-    // TODO: Mock the cartridge and PPU
-    let path = Path::new("/Users/xavier/code/rust/sen/roms/donkeykong.nes");
-    let mut file = File::open(path).unwrap();
-    let cartridge = Cartridge::load(&mut file);
+    make_cpu_with_mapper(0)
+}
+
+fn make_cpu_with_mapper(mapper: u8) -> Cpu {
+    make_cpu_with_rom(make_rom(mapper))
+}
+
+fn make_cpu_with_rom(rom: Vec<u8>) -> Cpu {
+    let cartridge = Cartridge::load(&mut Cursor::new(rom)).unwrap();
 
-    let ppu = Ppu::new();
-    let mut cpu = Cpu::new(cartridge, ppu);
+    let ppu = Ppu::new(cartridge.clone());
+    let memory = CpuMemory::new(cartridge, ppu, Controller::new());
+    let mut cpu = Cpu::new(memory);
     cpu.reset();
-    cpu.regs.pc = 0x0100;
+    cpu.pc = 0x0100;
     cpu
 }
 
@@ -28,14 +71,14 @@ fn lda_immediate() {
     cpu.store_byte(0x0101, 0xff);
     cpu.step();
 
-    assert_eq!(0xFF, cpu.regs.a);
+    assert_eq!(0xFF, cpu.a);
 }
 
 #[test]
 fn sta_absolute() {
     let mut cpu = make_cpu();
 
-    cpu.regs.a = 0xf9;
+    cpu.a = 0xf9;
     cpu.store_byte(0x0100, 0x8d);
     cpu.store_word(0x0101, 0x1234);
     cpu.step();
@@ -52,5 +95,1326 @@ fn lda_zero_page() {
     cpu.store_byte(0x88, 0xf9);
     cpu.step();
 
-    assert_eq!(0xf9, cpu.regs.a);
+    assert_eq!(0xf9, cpu.a);
+}
+
+#[test]
+fn irq_respects_interrupt_flag() {
+    // IRQ vector at $0200
+    let mut rom = make_rom(0);
+    rom[16 + 0x3FFE] = 0x00;
+    rom[16 + 0x3FFF] = 0x02;
+    let mut cpu = make_cpu_with_rom(rom);
+
+    cpu.store_byte(0x0100, 0xEA); // NOP
+    cpu.store_byte(0x0101, 0x58); // CLI
+    cpu.store_byte(0x0200, 0xEA); // NOP
+
+    // The APU frame IRQ stays asserted until $4015 is read
+    cpu.ram.apu.step(29829, |_| 0);
+    assert!(cpu.ram.irq());
+
+    // I is set after power up
+    assert_eq!(2, cpu.step_instruction());
+    assert_eq!(0x0101, cpu.pc);
+    assert_eq!(2, cpu.step_instruction());
+    assert_eq!(0x0102, cpu.pc);
+
+    // 7 cycles for the interrupt, then the NOP at the vector
+    let s = cpu.s;
+    assert_eq!(7 + 2, cpu.step_instruction());
+    assert_eq!(0x0201, cpu.pc);
+    assert_eq!(s.wrapping_sub(3), cpu.s);
+
+    let flags = cpu.load_byte(0x0100 + s as u16 - 2);
+    assert_eq!(0, flags & 0x10); // B clear
+    assert_eq!(0, flags & 0x04); // I was clear
+    assert_eq!(0x0102, cpu.load_word(0x0100 + s as u16 - 1));
+
+    // The handler runs with I set
+    cpu.store_byte(0x0201, 0xEA);
+    assert_eq!(2, cpu.step_instruction());
+}
+
+#[test]
+fn cartridge_bad_magic() {
+    let mut rom = make_rom(0);
+    rom[3] = 0;
+
+    match Cartridge::load(&mut Cursor::new(rom)) {
+        Err(CartridgeError::BadMagic(_)) => (),
+        other => panic!("Expected BadMagic, got {:?}", other),
+    }
+}
+
+#[test]
+fn cartridge_truncated_prg() {
+    let mut rom = make_rom(0);
+    rom.truncate(16 + 0x1000);
+
+    match Cartridge::load(&mut Cursor::new(rom)) {
+        Err(CartridgeError::TruncatedPrg { expected: 0x4000, actual: 0x1000 }) => (),
+        other => panic!("Expected TruncatedPrg, got {:?}", other),
+    }
+}
+
+#[test]
+fn cartridge_nes2_header() {
+    let header = NesHeader::parse(&[
+        0x4E, 0x45, 0x53, 0x1A,
+        0x02, // PRG LSB: 0x102 16KB banks with the MSB below
+        13 << 2 | 1, // CHR: 2^13 * 3 bytes
+        0x51, // Mapper bits 0-3, vertical mirroring
+        0xAB, // Mapper bits 4-7, NES 2.0, extended console type
+        0x32, // Submapper 3, mapper bits 8-11
+        0xF1, // CHR in exponent-multiplier notation, PRG MSB
+        0x97, // PRG NVRAM 64 << 9, PRG RAM 64 << 7
+        0x80, // CHR NVRAM 64 << 8, no CHR RAM
+        0x03, // Dendy
+        0x0A, // Extended console type
+        0x00,
+        0xEB, // Expansion device in bits 0-5
+    ]).unwrap();
+
+    assert!(header.nes2);
+    assert_eq!(0x2A5, header.mapper);
+    assert_eq!(3, header.submapper);
+    assert_eq!(0x102 * 0x4000, header.prg_rom_size);
+    assert_eq!(0x6000, header.chr_rom_size);
+    assert_eq!(0x2000, header.prg_ram_size);
+    assert_eq!(0x8000, header.prg_nvram_size);
+    assert_eq!(0, header.chr_ram_size);
+    assert_eq!(0x4000, header.chr_nvram_size);
+    assert_eq!(Timing::Dendy, header.timing);
+    assert_eq!(ConsoleType::Extended(0x0A), header.console);
+    assert_eq!(0x2B, header.expansion_device);
+    assert_eq!(Mirroring::Vertical, header.mirroring());
+}
+
+#[test]
+fn cartridge_ines_header() {
+    let header = NesHeader::parse(&[
+        0x4E, 0x45, 0x53, 0x1A,
+        0x02, 0x00, // 32KB PRG, CHR RAM
+        0x12, // Mapper bits 0-3, battery
+        0x41, // Mapper bits 4-7, VS System
+        0x02, // 16KB PRG RAM
+        0x01, // PAL
+        0, 0, 0, 0, 0, 0,
+    ]).unwrap();
+
+    assert!(!header.nes2);
+    assert_eq!(0x41, header.mapper);
+    assert_eq!(0x8000, header.prg_rom_size);
+    assert_eq!(0, header.chr_rom_size);
+    assert_eq!(0x2000, header.chr_ram_size);
+    assert_eq!(0, header.prg_ram_size);
+    assert_eq!(0x4000, header.prg_nvram_size);
+    assert_eq!(Timing::Pal, header.timing);
+    assert_eq!(ConsoleType::VsSystem, header.console);
+    assert_eq!(Mirroring::Horizontal, header.mirroring());
+}
+
+#[test]
+fn cartridge_ines_header_with_garbage() {
+    let mut bytes = [0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x21, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    bytes[7..].copy_from_slice(b"DiskDude!");
+    let header = NesHeader::parse(&bytes).unwrap();
+
+    // Only the lower nybble of the mapper, and the defaults for everything else
+    assert!(!header.nes2);
+    assert_eq!(0x02, header.mapper);
+    assert_eq!(0x2000, header.prg_ram_size);
+    assert_eq!(Timing::Ntsc, header.timing);
+    assert_eq!(ConsoleType::Nes, header.console);
+    assert_eq!(Mirroring::Vertical, header.mirroring());
+}
+
+#[test]
+fn cartridge_nes2_rom_size_too_large() {
+    let mut rom = make_rom(0);
+    rom[7] |= 0x08; // NES 2.0
+    rom[9] = 0x0F; // Exponent-multiplier PRG size
+    rom[4] = 63 << 2 | 3;
+
+    match Cartridge::load(&mut Cursor::new(rom)) {
+        Err(CartridgeError::InvalidHeader(_)) => (),
+        other => panic!("Expected InvalidHeader, got {:?}", other),
+    }
+}
+
+#[test]
+fn cartridge_huge_truncated_chr() {
+    let mut rom = make_rom(0);
+    rom[7] |= 0x08; // NES 2.0
+    rom[9] = 0xF0; // Exponent-multiplier CHR size, 48 MiB
+    rom[5] = 24 << 2 | 1;
+
+    match Cartridge::load(&mut Cursor::new(rom)) {
+        Err(CartridgeError::TruncatedChr { expected: 0x3000000, actual: 0x2000 }) => (),
+        other => panic!("Expected TruncatedChr, got {:?}", other),
+    }
+}
+
+#[test]
+fn cartridge_rom_too_small() {
+    let expect_invalid = |rom: Vec<u8>| match Cartridge::load(&mut Cursor::new(rom)) {
+        Err(CartridgeError::InvalidHeader(_)) => (),
+        other => panic!("Expected InvalidHeader, got {:?}", other),
+    };
+
+    // No PRG ROM at all
+    let mut rom = make_rom(0);
+    rom[4] = 0;
+    rom.truncate(16 + 0x2000);
+    expect_invalid(rom);
+
+    // 8KB of PRG ROM, less than one MMC1, UxROM or MMC3 bank
+    for &mapper in [1, 2, 4].iter() {
+        let mut rom = make_rom(mapper);
+        rom[7] |= 0x08; // NES 2.0
+        rom[9] = 0x0F; // Exponent-multiplier PRG size
+        rom[4] = 13 << 2;
+        rom.drain(16 + 0x2000..16 + 0x4000);
+        expect_invalid(rom);
+    }
+
+    // 4KB of CHR ROM
+    let mut rom = make_rom(0);
+    rom[7] |= 0x08;
+    rom[9] = 0xF0;
+    rom[5] = 12 << 2;
+    rom.truncate(16 + 0x4000 + 0x1000);
+    expect_invalid(rom);
+
+    // NROM mirrors 8KB just fine
+    let mut rom = make_rom(0);
+    rom[7] |= 0x08;
+    rom[9] = 0x0F;
+    rom[4] = 13 << 2;
+    rom.drain(16 + 0x2000..16 + 0x4000);
+    make_cpu_with_rom(rom);
+}
+
+#[test]
+fn cartridge_unsupported_mapper() {
+    match Cartridge::load(&mut Cursor::new(make_rom(0xFF))) {
+        Err(CartridgeError::UnsupportedMapper(0xFF)) => (),
+        other => panic!("Expected UnsupportedMapper, got {:?}", other),
+    }
+}
+
+#[test]
+fn cartridge_trainer() {
+    let mut rom = make_rom(0);
+    rom[6] |= 0x04;
+    let trainer: Vec<u8> = (0..512).map(|i| i as u8).collect();
+    rom.splice(16..16, trainer);
+
+    let cartridge = Cartridge::load(&mut Cursor::new(rom)).unwrap();
+    let mut mapper = cartridge.mapper.borrow_mut();
+    assert_eq!(0x00, mapper.prg_load(0x7000));
+    assert_eq!(0xFF, mapper.prg_load(0x70FF));
+    assert_eq!(0xFF, mapper.prg_load(0x71FF));
+}
+
+#[test]
+fn cartridge_chr_ram() {
+    let mut rom = make_rom(0);
+    rom[5] = 0;
+    rom.truncate(16 + 0x4000);
+
+    let cartridge = Cartridge::load(&mut Cursor::new(rom)).unwrap();
+    let mut ppu = Ppu::new(cartridge);
+    ppu.store(0x2006, 0x12);
+    ppu.store(0x2006, 0x34);
+    ppu.store(0x2007, 0x56);
+    assert_eq!(0x56, ppu.vram_load(0x1234));
+}
+
+#[test]
+fn cartridge_save_ram_round_trip() {
+    let cartridge = Cartridge::load(&mut Cursor::new(make_rom(1))).unwrap();
+    cartridge.mapper.borrow_mut().prg_store(0x6010, 0x42);
+
+    let mut sav = Vec::new();
+    cartridge.write_save_ram(&mut sav).unwrap();
+    assert_eq!(0x2000, sav.len());
+
+    let other = Cartridge::load(&mut Cursor::new(make_rom(1))).unwrap();
+    other.read_save_ram(&mut Cursor::new(sav)).unwrap();
+    assert_eq!(0x42, other.mapper.borrow_mut().prg_load(0x6010));
+}
+
+#[test]
+fn save_state_round_trip() {
+    let mut cpu = make_cpu_with_mapper(1);
+    cpu.store_byte(0x0100, 0xa9);
+    cpu.store_byte(0x0101, 0x42);
+    cpu.store_byte(0x6000, 0x99);
+    cpu.step();
+
+    let mut state = Vec::new();
+    savestate::save(&cpu, &mut state).unwrap();
+
+    let mut other = make_cpu_with_mapper(1);
+    savestate::load(&mut other, &mut Cursor::new(state)).unwrap();
+    assert_eq!(cpu.pc, other.pc);
+    assert_eq!(0x42, other.a);
+    assert_eq!(0xa9, other.load_byte(0x0100));
+    assert_eq!(0x99, other.load_byte(0x6000));
+}
+
+#[test]
+fn save_state_wrong_cartridge() {
+    let cpu = make_cpu_with_mapper(1);
+    let mut state = Vec::new();
+    savestate::save(&cpu, &mut state).unwrap();
+
+    let mut other = make_cpu();
+    match savestate::load(&mut other, &mut Cursor::new(state)) {
+        Err(savestate::SaveStateError::WrongCartridge) => (),
+        other => panic!("Expected WrongCartridge, got {:?}", other),
+    }
+}
+
+struct RecordingSink(Rc<RefCell<Vec<f32>>>);
+
+impl AudioSink for RecordingSink {
+    fn sample(&mut self, sample: f32) {
+        self.0.borrow_mut().push(sample);
+    }
+}
+
+#[test]
+fn apu_pulse_square_wave() {
+    let samples = Rc::new(RefCell::new(Vec::new()));
+    let mut apu = Apu::new();
+    apu.set_sink(Some(Box::new(RecordingSink(samples.clone()))));
+
+    apu.store(0x4015, 0x01);
+    apu.store(0x4000, 0xBF); // 50% duty, halt, constant volume 15
+    apu.store(0x4002, 0xFF);
+    apu.store(0x4003, 0x00);
+    apu.step(2048 * 8, |_| 0);
+
+    // The idle triangle channel adds a DC offset
+    let samples = samples.borrow();
+    assert_eq!(2048 * 8, samples.len());
+    let low = samples.iter().cloned().fold(1.0, f32::min);
+    let high = samples.iter().filter(|&&s| s > low).count();
+    assert!(high > samples.len() * 4 / 10 && high < samples.len() * 6 / 10);
+}
+
+#[test]
+fn apu_pulse_disabled_is_silent() {
+    let mut apu = Apu::new();
+    apu.store(0x4000, 0xBF);
+    apu.store(0x4002, 0xFF);
+    apu.store(0x4003, 0x00);
+    apu.step(1000, |_| 0);
+
+    assert_eq!(Apu::new().output(), apu.output());
+}
+
+#[test]
+fn apu_load_state_masks_pulse_registers() {
+    let mut apu = Apu::new();
+    apu.store(0x4015, 0x01);
+    apu.store(0x4000, 0xBF);
+    apu.store(0x4002, 0xFF);
+    apu.store(0x4003, 0x00);
+
+    // Pulse 1 duty and sequence come first
+    let mut state = Vec::new();
+    apu.save_state(&mut state).unwrap();
+    state[0] = 0x07;
+    state[1] = 0xFF;
+
+    let mut other = Apu::new();
+    other.load_state(&mut Cursor::new(state)).unwrap();
+    assert!(other.output() > 0.0); // Step 7 of the negated 25% duty is high
+}
+
+#[test]
+fn apu_load_state_checks_triangle_and_noise() {
+    let mut state = Vec::new();
+    Triangle::new().save_state(&mut state).unwrap();
+    state[0] = 0xFF; // Sequence
+    let mut triangle = Triangle::new();
+    triangle.load_state(&mut Cursor::new(state)).unwrap();
+    assert_eq!(15, triangle.output());
+
+    // The timer period has to be one of the table's
+    let mut state = Vec::new();
+    Noise::new().save_state(&mut state).unwrap();
+    state[5] = 0;
+    state[6] = 0;
+    assert!(Noise::new().load_state(&mut Cursor::new(state)).is_err());
+}
+
+#[test]
+fn apu_load_state_checks_dmc() {
+    let mut state = Vec::new();
+    Dmc::new().save_state(&mut state).unwrap();
+    assert!(Dmc::new().load_state(&mut Cursor::new(state.clone())).is_ok());
+
+    // Timer period
+    let mut bad = state.clone();
+    bad[4] = 0;
+    bad[5] = 0;
+    assert!(Dmc::new().load_state(&mut Cursor::new(bad)).is_err());
+
+    // Bits remaining, right after the shift register
+    let mut bad = state.clone();
+    bad[18] = 0;
+    assert!(Dmc::new().load_state(&mut Cursor::new(bad)).is_err());
+    let mut bad = state;
+    bad[18] = 9;
+    assert!(Dmc::new().load_state(&mut Cursor::new(bad)).is_err());
+}
+
+#[test]
+fn apu_frame_irq() {
+    let mut apu = Apu::new();
+    apu.step(29828, |_| 0);
+    assert!(!apu.irq());
+    apu.step(1, |_| 0);
+    assert!(apu.irq());
+
+    assert_eq!(0x40, apu.read_status() & 0x40);
+    assert!(!apu.irq());
+}
+
+#[test]
+fn apu_frame_irq_inhibit_and_five_step() {
+    let mut apu = Apu::new();
+    apu.store(0x4017, 0x40);
+    apu.step(40000, |_| 0);
+    assert!(!apu.irq());
+
+    apu.store(0x4017, 0x80);
+    apu.step(40000, |_| 0);
+    assert!(!apu.irq());
+}
+
+#[test]
+fn apu_length_counter_status() {
+    let mut apu = Apu::new();
+    apu.store(0x4015, 0x0F);
+    apu.store(0x400B, 0x08); // Triangle, length index 1 (254)
+    apu.store(0x400F, 0x00); // Noise, length index 0 (10)
+    assert_eq!(0x0C, apu.read_status() & 0x0F);
+
+    // 10 half frames later the noise is done, the triangle is still going
+    apu.store(0x4017, 0x40);
+    apu.step(29830 * 5, |_| 0);
+    assert_eq!(0x04, apu.read_status() & 0x0F);
+
+    apu.store(0x4015, 0x00);
+    assert_eq!(0x00, apu.read_status() & 0x0F);
+}
+
+#[test]
+fn apu_dmc_fetches_sample_and_raises_irq() {
+    let mut apu = Apu::new();
+    let mut fetched = Vec::new();
+    apu.store(0x4010, 0x8F); // IRQ enabled, no loop, fastest rate
+    apu.store(0x4012, 0x01); // $C040
+    apu.store(0x4013, 0x01); // 17 bytes
+    apu.store(0x4015, 0x10);
+    assert_eq!(0x10, apu.read_status() & 0x10);
+
+    // Each byte lasts 8 * 54 cycles
+    let stall = apu.step(54 * 8 * 17, |address| { fetched.push(address); 0xFF });
+    assert_eq!(17, fetched.len());
+    assert_eq!(0xC040, fetched[0]);
+    assert_eq!(0xC050, fetched[16]);
+    assert_eq!(17 * 4, stall);
+
+    assert!(apu.irq());
+    assert_eq!(0x80, apu.read_status() & 0x90);
+    apu.store(0x4015, 0x00);
+    assert!(!apu.irq());
+}
+
+#[test]
+fn dmc_dma_stalls_cpu() {
+    let mut cpu = make_cpu();
+    cpu.ram.apu.store(0x4013, 0x00); // 1 byte
+    cpu.ram.apu.store(0x4015, 0x10);
+    cpu.ram.store(0x0100, 0xEA); // NOP
+    cpu.ram.store(0x0101, 0xEA);
+
+    // The sample byte is fetched during the first NOP
+    assert_eq!(2 + 4, cpu.step_instruction());
+    assert_eq!(2, cpu.step_instruction());
+}
+
+#[test]
+fn resampler_output_rate() {
+    let mut resampler = Resampler::new(CPU_CLOCK_RATE, 48000);
+    let mut samples = Vec::new();
+
+    // One second of a 1kHz square wave
+    for i in 0..CPU_CLOCK_RATE as u32 {
+        resampler.sample(if i % 1790 < 895 { 0.5 } else { 0.0 });
+    }
+    resampler.read_samples(&mut samples);
+
+    assert!((samples.len() as i32 - 48000).abs() < 16);
+    assert!(samples.iter().all(|s| s.abs() < 1.0));
+    assert!(samples.iter().any(|s| *s > 0.1));
+
+    resampler.set_rate_adjustment(1.005);
+    samples.clear();
+    for _ in 0..CPU_CLOCK_RATE as u32 {
+        resampler.sample(0.0);
+    }
+    resampler.read_samples(&mut samples);
+    assert!((samples.len() as i32 - 48240).abs() < 2);
+}
+
+#[test]
+fn wav_writer_header() {
+    let mut wav = WavWriter::new(Cursor::new(Vec::new()), 48000).unwrap();
+    wav.write_sample(1.0).unwrap();
+    wav.write_sample(-2.0).unwrap();
+    let data = wav.finish().unwrap().into_inner();
+
+    assert_eq!(48, data.len());
+    assert_eq!(b"RIFF", &data[0..4]);
+    assert_eq!(&40u32.to_le_bytes(), &data[4..8]);
+    assert_eq!(&48000u32.to_le_bytes(), &data[24..28]);
+    assert_eq!(&4u32.to_le_bytes(), &data[40..44]);
+    assert_eq!(&32767i16.to_le_bytes(), &data[44..46]);
+    assert_eq!(&(-32767i16).to_le_bytes(), &data[46..48]);
+}
+
+#[test]
+fn apu_records_wav_per_channel() {
+    let dir = env::temp_dir().join(format!("sen-record-{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("out.wav");
+
+    let mut apu = Apu::new();
+    apu.start_recording(&path, true).unwrap();
+    assert!(apu.recording());
+    apu.store(0x4015, 0x01);
+    apu.store(0x4000, 0xBF);
+    apu.store(0x4002, 0xFF);
+    apu.store(0x4003, 0x00);
+    apu.step(CPU_CLOCK_RATE as u64 / 10, |_| 0);
+    apu.stop_recording().unwrap();
+    assert!(!apu.recording());
+
+    // 0.1s at 48kHz
+    let mixed = fs::read(&path).unwrap();
+    let samples = (mixed.len() - 44) / 2;
+    assert!((samples as i32 - 4800).abs() < 16);
+    let pulse1 = fs::read(dir.join("out.pulse1.wav")).unwrap();
+    assert_eq!(mixed.len(), pulse1.len());
+    assert!(pulse1[44..].iter().any(|b| *b != 0));
+    assert!(fs::read(dir.join("out.noise.wav")).unwrap()[44..].iter().all(|b| *b == 0));
+
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+// CHR RAM, vertical mirroring, tile 1 solid colour 1 on a black backdrop,
+// nametable 0 blank and nametable 1 filled with tile 1
+fn make_scroll_ppu() -> Ppu {
+    let mut rom = vec![0x4E, 0x45, 0x53, 0x1A, 1, 0, 0x01, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+    rom.extend(vec![0; 0x4000]);
+    let cartridge = Cartridge::load(&mut Cursor::new(rom)).unwrap();
+    let mut ppu = Ppu::new(cartridge);
+
+    ppu.store(0x2006, 0x00);
+    ppu.store(0x2006, 0x10);
+    for i in 0..16 {
+        ppu.store(0x2007, if i < 8 { 0xFF } else { 0x00 });
+    }
+
+    ppu.store(0x2006, 0x24);
+    ppu.store(0x2006, 0x00);
+    for _ in 0..0x3C0 {
+        ppu.store(0x2007, 0x01);
+    }
+
+    ppu.store(0x2006, 0x3F);
+    ppu.store(0x2006, 0x00);
+    ppu.store(0x2007, 0x0F);
+    ppu.store(0x2007, 0x30);
+    ppu
+}
+
+// Runs from the next pre-render line until VBlank
+fn render_frame(ppu: &mut Ppu) {
+    run_to_scanline(ppu, 261);
+    render_frame_end(ppu);
+}
+
+fn render_frame_end(ppu: &mut Ppu) {
+    ppu.new_frame = false;
+    while !ppu.new_frame {
+        ppu.step(1);
+    }
+}
+
+fn run_to_scanline(ppu: &mut Ppu, scanline: u16) {
+    while ppu.scanline != scanline {
+        ppu.step(1);
+    }
+}
+
+fn pixel_is_white(ppu: &Ppu, x: usize, y: usize) -> bool {
+    ppu.frame()[y * 256 + x] == 0x30
+}
+
+#[test]
+fn ppu_scroll_x_crosses_nametables() {
+    let mut ppu = make_scroll_ppu();
+    ppu.load(0x2002);
+    ppu.store(0x2000, 0x00);
+    ppu.store(0x2005, 128 + 3);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x08);
+    render_frame(&mut ppu);
+
+    assert!(!pixel_is_white(&ppu, 0, 10));
+    assert!(!pixel_is_white(&ppu, 124, 10));
+    assert!(pixel_is_white(&ppu, 125, 10));
+    assert!(pixel_is_white(&ppu, 250, 100));
+}
+
+#[test]
+fn ppu_nametable_select() {
+    let mut ppu = make_scroll_ppu();
+    ppu.load(0x2002);
+    ppu.store(0x2000, 0x01);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x0A); // Background, left column included
+    render_frame(&mut ppu);
+
+    assert!(pixel_is_white(&ppu, 0, 0));
+    assert!(pixel_is_white(&ppu, 200, 239));
+}
+
+
+#[test]
+fn ppu_split_scroll() {
+    let mut ppu = make_scroll_ppu();
+    ppu.load(0x2002);
+    ppu.store(0x2000, 0x00);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x0A); // Background, left column included
+    render_frame(&mut ppu);
+
+    // Switch nametables while line 99 is drawn, it shows from the next line
+    run_to_scanline(&mut ppu, 261);
+    run_to_scanline(&mut ppu, 99);
+    ppu.step(20);
+    ppu.store(0x2000, 0x01);
+    render_frame_end(&mut ppu);
+
+    assert!(!pixel_is_white(&ppu, 0, 99));
+    assert!(pixel_is_white(&ppu, 0, 100));
+    assert!(pixel_is_white(&ppu, 0, 239));
+}
+
+// Writes a register through the MMC1 serial port, low bit first
+fn mmc1_write(cpu: &mut Cpu, address: u16, value: u8) {
+    for bit in 0..5 {
+        cpu.ram.store(address, (value >> bit) & 1);
+    }
+}
+
+#[test]
+fn mmc1_prg_modes() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(1, 8, 4));
+
+    // Powers up with the last bank fixed at $C000
+    assert_eq!(0, cpu.ram.load(0x8000));
+    assert_eq!(7, cpu.ram.load(0xC000));
+
+    mmc1_write(&mut cpu, 0xE000, 3);
+    assert_eq!(3, cpu.ram.load(0x8000));
+    assert_eq!(7, cpu.ram.load(0xFFFF));
+
+    // First bank fixed at $8000
+    mmc1_write(&mut cpu, 0x8000, 0x08);
+    assert_eq!(0, cpu.ram.load(0x8000));
+    assert_eq!(3, cpu.ram.load(0xC000));
+
+    // 32KB, the low bit of the bank is ignored
+    mmc1_write(&mut cpu, 0x8000, 0x00);
+    assert_eq!(2, cpu.ram.load(0x8000));
+    assert_eq!(3, cpu.ram.load(0xC000));
+}
+
+#[test]
+fn mmc1_shift_register_reset() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(1, 8, 4));
+    mmc1_write(&mut cpu, 0x8000, 0x00);
+
+    // Nothing happens until the fifth write
+    for _ in 0..4 {
+        cpu.ram.store(0xE000, 1);
+    }
+    assert_eq!(0, cpu.ram.load(0x8000));
+
+    // Bit 7 drops the pending bits and goes back to fixing the last bank
+    cpu.ram.store(0xE000, 0x80);
+    assert_eq!(7, cpu.ram.load(0xC000));
+    mmc1_write(&mut cpu, 0xE000, 5);
+    assert_eq!(5, cpu.ram.load(0x8000));
+    assert_eq!(7, cpu.ram.load(0xC000));
+}
+
+#[test]
+fn mmc1_chr_banks() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(1, 2, 4));
+
+    // Two 4KB banks
+    mmc1_write(&mut cpu, 0x8000, 0x1C);
+    mmc1_write(&mut cpu, 0xA000, 5);
+    mmc1_write(&mut cpu, 0xC000, 2);
+    assert_eq!(0x85, cpu.ram.ppu.vram_load(0x0000));
+    assert_eq!(0x82, cpu.ram.ppu.vram_load(0x1FFF));
+
+    // One 8KB bank, ignoring the low bit
+    mmc1_write(&mut cpu, 0x8000, 0x0C);
+    assert_eq!(0x84, cpu.ram.ppu.vram_load(0x0000));
+    assert_eq!(0x85, cpu.ram.ppu.vram_load(0x1000));
+}
+
+#[test]
+fn uxrom_prg_bank() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(2, 8, 1));
+    cpu.ram.store(0x8000, 3);
+    assert_eq!(3, cpu.ram.load(0x8000));
+    assert_eq!(7, cpu.ram.load(0xC000));
+}
+
+#[test]
+fn cnrom_chr_bank() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(3, 2, 4));
+    cpu.ram.store(0x8000, 2);
+    assert_eq!(0x84, cpu.ram.ppu.vram_load(0x0000));
+    assert_eq!(0x85, cpu.ram.ppu.vram_load(0x1FFF));
+    assert_eq!(1, cpu.ram.load(0xC000));
+}
+
+#[test]
+fn axrom_prg_bank_and_nametable() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(7, 8, 1));
+    cpu.ram.store(0x8000, 0x12);
+    assert_eq!(4, cpu.ram.load(0x8000));
+    assert_eq!(5, cpu.ram.load(0xC000));
+
+    // Every nametable shows the upper screen
+    cpu.ram.ppu.vram_store(0x2000, 0x42);
+    assert_eq!(0x42, cpu.ram.ppu.vram_load(0x2C00));
+
+    // Then the lower one, which is still blank
+    cpu.ram.store(0x8000, 0x02);
+    assert_eq!(0x00, cpu.ram.ppu.vram_load(0x2400));
+    cpu.ram.ppu.vram_store(0x2400, 0x24);
+    assert_eq!(0x24, cpu.ram.ppu.vram_load(0x2800));
+
+    cpu.ram.store(0x8000, 0x12);
+    assert_eq!(0x42, cpu.ram.ppu.vram_load(0x2400));
+}
+
+#[test]
+fn gxrom_prg_and_chr_banks() {
+    let mut cpu = make_cpu_with_rom(make_banked_rom(66, 8, 4));
+    cpu.ram.store(0x8000, 0x21);
+    assert_eq!(4, cpu.ram.load(0x8000));
+    assert_eq!(5, cpu.ram.load(0xFFFF));
+    assert_eq!(0x82, cpu.ram.ppu.vram_load(0x0000));
+    assert_eq!(0x83, cpu.ram.ppu.vram_load(0x1000));
+}
+
+#[test]
+fn axrom_and_gxrom_mirror_16k_prg() {
+    for &mapper in [7, 66].iter() {
+        let mut cpu = make_cpu_with_rom(make_banked_rom(mapper, 1, 1));
+        cpu.ram.store(0x8000, 0x33);
+        assert_eq!(0, cpu.ram.load(0x8000));
+        assert_eq!(0, cpu.ram.load(0xC000));
+    }
+}
+
+// Writes each nametable's number into it, in order, and reads them all back
+fn nametables_after_writes(flags_6: u8) -> [u8; 4] {
+    let mut rom = make_rom(0);
+    rom[6] = flags_6;
+    let mut cpu = make_cpu_with_rom(rom);
+
+    for table in 0..4 {
+        cpu.ram.ppu.vram_store(0x2000 + table * 0x400 + 0x123, table as u8 + 1);
+    }
+    let mut tables = [0; 4];
+    for (table, value) in tables.iter_mut().enumerate() {
+        *value = cpu.ram.ppu.vram_load(0x2000 + table as u16 * 0x400 + 0x123);
+    }
+    tables
+}
+
+#[test]
+fn header_nametable_mirroring() {
+    assert_eq!([2, 2, 4, 4], nametables_after_writes(0x00)); // Horizontal
+    assert_eq!([3, 4, 3, 4], nametables_after_writes(0x01)); // Vertical
+    assert_eq!([1, 2, 3, 4], nametables_after_writes(0x08)); // Four-screen
+    assert_eq!([1, 2, 3, 4], nametables_after_writes(0x09)); // Four-screen wins over vertical
+}
+
+#[test]
+fn four_screen_extra_vram() {
+    let mut rom = make_rom(0);
+    rom[6] = 0x08;
+    let mut cpu = make_cpu_with_rom(rom);
+
+    // The whole 2KB on the cartridge
+    for offset in 0..0x800 {
+        cpu.ram.ppu.vram_store(0x2800 + offset, offset as u8 ^ 0x5A);
+    }
+    for offset in 0..0x800 {
+        assert_eq!(offset as u8 ^ 0x5A, cpu.ram.ppu.vram_load(0x2800 + offset));
+    }
+    // $3000-$3EFF mirrors it too
+    assert_eq!(0x5A, cpu.ram.ppu.vram_load(0x3800));
+    assert_eq!(0x2F ^ 0x5A, cpu.ram.ppu.vram_load(0x3C2F));
+    assert_eq!(0, cpu.ram.ppu.vram_load(0x2000));
+    assert_eq!(0, cpu.ram.ppu.vram_load(0x27FF));
+}
+
+#[test]
+fn mmc3_irq_clocked_by_a12() {
+    let mut cpu = make_cpu_with_mapper(4);
+    cpu.ram.store(0xC000, 9); // Latch
+    cpu.ram.store(0xC001, 0); // Reload
+    cpu.ram.store(0xE001, 0); // Enable
+    cpu.ram.ppu.store(0x2000, 0x08); // Sprites at $1000, background at $0000
+    cpu.ram.ppu.store(0x2001, 0x18);
+
+    // Reloaded on the pre-render line, then decremented once per line
+    run_to_scanline(&mut cpu.ram.ppu, 261);
+    run_to_scanline(&mut cpu.ram.ppu, 8);
+    assert!(!cpu.ram.irq());
+    run_to_scanline(&mut cpu.ram.ppu, 9);
+    cpu.ram.ppu.step(80); // Past the sprite fetches
+    assert!(cpu.ram.irq());
+}
+
+#[test]
+fn ppu_odd_frames_skip_a_dot_when_rendering() {
+    let mut ppu = make_scroll_ppu();
+    ppu.store(0x2001, 0x08);
+    render_frame(&mut ppu);
+    let (scanline, cycle) = (ppu.scanline, ppu.cycle);
+
+    // An even and an odd frame: 341 * 262 * 2 - 1 dots
+    ppu.step((341 * 262 * 2 - 1) / 3);
+    assert_eq!((scanline, cycle), (ppu.scanline, ppu.cycle));
+}
+
+// Sprite n at (x, y) using the solid tile 1, every other sprite off screen
+fn place_sprites(ppu: &mut Ppu, sprites: &[(u8, u8)]) {
+    for byte in ppu.oam_data.iter_mut() {
+        *byte = 0xFF;
+    }
+    for (n, &(x, y)) in sprites.iter().enumerate() {
+        ppu.oam_data[n * 4] = y;
+        ppu.oam_data[n * 4 + 1] = 0x01;
+        ppu.oam_data[n * 4 + 2] = 0x00;
+        ppu.oam_data[n * 4 + 3] = x;
+    }
+}
+
+#[test]
+fn ppu_sprite_zero_hit() {
+    let mut ppu = make_scroll_ppu();
+    place_sprites(&mut ppu, &[(100, 50)]);
+    ppu.store(0x2000, 0x01); // Opaque background everywhere
+    ppu.store(0x2001, 0x1E);
+
+    // Drawn from line 51
+    run_to_scanline(&mut ppu, 261);
+    run_to_scanline(&mut ppu, 51);
+    assert_eq!(0, ppu.load(0x2002) & 0x40);
+    run_to_scanline(&mut ppu, 52);
+    assert_eq!(0x40, ppu.load(0x2002) & 0x40);
+
+    // Cleared on the pre-render line
+    run_to_scanline(&mut ppu, 0);
+    assert_eq!(0, ppu.load(0x2002) & 0x40);
+}
+
+#[test]
+fn ppu_sprite_zero_hit_needs_opaque_background() {
+    let mut ppu = make_scroll_ppu();
+    place_sprites(&mut ppu, &[(100, 50)]);
+    ppu.store(0x2000, 0x00); // Transparent background
+    ppu.store(0x2001, 0x1E);
+    render_frame(&mut ppu);
+    assert_eq!(0, ppu.load(0x2002) & 0x40);
+
+    // Never at x=255
+    place_sprites(&mut ppu, &[(255, 50)]);
+    ppu.store(0x2000, 0x01);
+    render_frame(&mut ppu);
+    assert_eq!(0, ppu.load(0x2002) & 0x40);
+}
+
+#[test]
+fn ppu_sprite_overflow() {
+    let mut ppu = make_scroll_ppu();
+    ppu.store(0x2001, 0x18);
+
+    place_sprites(&mut ppu, &[(0, 100); 8]);
+    render_frame(&mut ppu);
+    assert_eq!(0, ppu.load(0x2002) & 0x20);
+
+    place_sprites(&mut ppu, &[(0, 100); 9]);
+    render_frame(&mut ppu);
+    assert_eq!(0x20, ppu.load(0x2002) & 0x20);
+}
+
+#[test]
+fn ppu_sprite_overflow_hardware_bug() {
+    let mut ppu = make_scroll_ppu();
+    ppu.store(0x2001, 0x18);
+
+    // 8 sprites on line 101, then sprite 8 is off the line but sprite 9's tile
+    // number is read as a Y coordinate, and it is in range
+    place_sprites(&mut ppu, &[(0, 100); 8]);
+    ppu.oam_data[9 * 4 + 1] = 100;
+    render_frame(&mut ppu);
+    assert_eq!(0x20, ppu.load(0x2002) & 0x20);
+}
+
+fn write_vram(ppu: &mut Ppu, address: u16, data: &[u8]) {
+    ppu.store(0x2006, (address >> 8) as u8);
+    ppu.store(0x2006, address as u8);
+    for byte in data {
+        ppu.store(0x2007, *byte);
+    }
+}
+
+fn pixel(ppu: &Ppu, x: usize, y: usize) -> u32 {
+    ppu.palette()[ppu.frame()[y * 256 + x] as usize]
+}
+
+#[test]
+fn ppu_8x16_sprites() {
+    let mut ppu = make_scroll_ppu();
+    // Tiles $1020 (color 1) and $1030 (color 2) in the right pattern table
+    write_vram(&mut ppu, 0x1020, &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+    write_vram(&mut ppu, 0x1030, &[0, 0, 0, 0, 0, 0, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+    write_vram(&mut ppu, 0x3F11, &[0x30, 0x16]);
+
+    // Tile index 3: odd, so $1000 even though PPUCTRL selects $0000 for sprites
+    place_sprites(&mut ppu, &[(100, 50)]);
+    ppu.oam_data[1] = 0x03;
+    ppu.store(0x2000, 0x20);
+    ppu.store(0x2001, 0x14);
+    render_frame(&mut ppu);
+
+    assert_eq!(0xFFFEFF, pixel(&ppu, 100, 51));
+    assert_eq!(0xFFFEFF, pixel(&ppu, 107, 58));
+    assert_eq!(0xB53120, pixel(&ppu, 100, 59));
+    assert_eq!(0xB53120, pixel(&ppu, 107, 66));
+    assert!(pixel(&ppu, 100, 67) != 0xB53120);
+
+    // Flipped vertically, the bottom tile comes first
+    ppu.oam_data[2] = 0x80;
+    render_frame(&mut ppu);
+    assert_eq!(0xB53120, pixel(&ppu, 100, 51));
+    assert_eq!(0xFFFEFF, pixel(&ppu, 100, 66));
+}
+
+#[test]
+fn ppu_palette_mirrors_and_backdrop() {
+    let mut ppu = make_scroll_ppu();
+    write_vram(&mut ppu, 0x3F10, &[0x16]);
+    write_vram(&mut ppu, 0x3F00, &[]);
+    assert_eq!(0x16, ppu.load(0x2007));
+
+    // Transparent background pixels use $3F00 whatever their palette
+    write_vram(&mut ppu, 0x23C0, &[0xFF; 64]);
+    write_vram(&mut ppu, 0x3F0C, &[0x30]);
+    ppu.store(0x2000, 0x00);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x0A);
+    render_frame(&mut ppu);
+    assert_eq!(0xB53120, pixel(&ppu, 0, 0));
+    assert_eq!(0xB53120, pixel(&ppu, 255, 239));
+}
+
+#[test]
+fn ppu_sprite_priority() {
+    let mut ppu = make_scroll_ppu();
+    write_vram(&mut ppu, 0x3F11, &[0x16]);
+    place_sprites(&mut ppu, &[(200, 50), (50, 50)]);
+    ppu.oam_data[2] = 0x20; // Sprite 0 behind the background
+
+    // Opaque background on the right half only
+    ppu.store(0x2000, 0x00);
+    ppu.store(0x2005, 128);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x1E);
+    render_frame(&mut ppu);
+    assert_eq!(0xFFFEFF, pixel(&ppu, 200, 51));
+    assert_eq!(0xB53120, pixel(&ppu, 50, 51));
+
+    // In front of the background
+    ppu.oam_data[2] = 0x00;
+    render_frame(&mut ppu);
+    assert_eq!(0xB53120, pixel(&ppu, 200, 51));
+}
+
+#[test]
+fn ppu_left_column_clipping() {
+    let mut ppu = make_scroll_ppu();
+    write_vram(&mut ppu, 0x3F11, &[0x16]);
+    place_sprites(&mut ppu, &[(0, 100)]);
+    ppu.store(0x2000, 0x01);
+
+    ppu.store(0x2001, 0x18);
+    render_frame(&mut ppu);
+    assert_eq!(0x000000, pixel(&ppu, 0, 50));
+    assert_eq!(0x000000, pixel(&ppu, 7, 101));
+    assert_eq!(0xFFFEFF, pixel(&ppu, 8, 50));
+
+    ppu.store(0x2001, 0x1E);
+    render_frame(&mut ppu);
+    assert_eq!(0xFFFEFF, pixel(&ppu, 0, 50));
+    assert_eq!(0xB53120, pixel(&ppu, 7, 101));
+}
+
+#[test]
+fn ppu_grayscale_and_emphasis() {
+    let mut ppu = make_scroll_ppu();
+    write_vram(&mut ppu, 0x3F01, &[0x16]);
+    ppu.store(0x2000, 0x01);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+
+    ppu.store(0x2001, 0x0A);
+    render_frame(&mut ppu);
+    assert_eq!(0xB53120, pixel(&ppu, 100, 100));
+
+    ppu.store(0x2001, 0x0B);
+    render_frame(&mut ppu);
+    assert_eq!(0xADADAD, pixel(&ppu, 100, 100));
+
+    // Green emphasis darkens red and blue
+    ppu.store(0x2001, 0x4A);
+    render_frame(&mut ppu);
+    assert_eq!(0x94311A, pixel(&ppu, 100, 100));
+}
+
+#[test]
+fn emphasized_palette() {
+    let colors = palette::emphasized(&palette::PALETTE_RGB);
+    assert_eq!(palette::EMPHASIZED_SIZE, colors.len());
+    assert_eq!(&palette::PALETTE_RGB[..], &colors[..64]);
+
+    // All three bits darken every component, but leave black columns alone
+    let all = 7 << 6;
+    assert_eq!(0x535353, colors[all]);
+    assert_eq!(0x404040, colors[all | 0x2D]);
+    assert_eq!(0x4F4F4F, colors[0x2D]);
+    assert_eq!(0x000000, colors[all | 0x0E]);
+}
+
+#[test]
+fn load_palette_files() {
+    let mut bytes = Vec::new();
+    for color in palette::PALETTE_RGB.iter() {
+        bytes.extend_from_slice(&[(color >> 16) as u8, (color >> 8) as u8, *color as u8]);
+    }
+    let colors = palette::load(&mut Cursor::new(&bytes)).unwrap();
+    assert_eq!(palette::emphasized(&palette::PALETTE_RGB), colors);
+
+    // With the emphasized colors included, they are used as is
+    let full: Vec<u8> = (0..1536).map(|i| i as u8).collect();
+    let colors = palette::load(&mut Cursor::new(&full)).unwrap();
+    assert_eq!(512, colors.len());
+    assert_eq!(0x000102, colors[0]);
+    assert_eq!(0xFDFEFF, colors[511]);
+
+    match palette::load(&mut Cursor::new(&bytes[..190])) {
+        Err(palette::PaletteError::BadSize(190)) => (),
+        Err(e) => panic!("unexpected error: {}", e),
+        Ok(_) => panic!("a truncated palette shouldn't load"),
+    }
+}
+
+#[test]
+fn ntsc_palette() {
+    let colors = palette::generate(&palette::NtscSettings::new());
+    assert_eq!(palette::EMPHASIZED_SIZE, colors.len());
+    assert_eq!(0x000000, colors[0x0F]);
+    assert_eq!(0xFFFFFF, colors[0x20]);
+
+    // Gray column
+    for &gray in [0x00, 0x10, 0x2D].iter() {
+        let color = colors[gray];
+        assert_eq!(color & 0xFF, color >> 16);
+        assert_eq!(color & 0xFF, (color >> 8) & 0xFF);
+    }
+
+    // $16 is red, $1A green and $12 blue
+    let component = |color: u32, shift: u32| (color >> shift) & 0xFF;
+    assert!(component(colors[0x16], 16) > component(colors[0x16], 8));
+    assert!(component(colors[0x1A], 8) > component(colors[0x1A], 16));
+    assert!(component(colors[0x12], 0) > component(colors[0x12], 16));
+
+    // Red emphasis leaves red mostly alone and darkens cyan
+    let red = 1 << 6;
+    assert!(component(colors[red | 0x2C], 0) < component(colors[0x2C], 0));
+
+    let mut settings = palette::NtscSettings::new();
+    settings.saturation = 0.0;
+    let grays = palette::generate(&settings);
+    assert_eq!(grays[0x16] & 0xFF, grays[0x16] >> 16);
+
+    settings = palette::NtscSettings::new();
+    settings.brightness = 0.1;
+    assert!(palette::generate(&settings)[0x00] > colors[0x00]);
+}
+
+#[test]
+fn ppu_custom_palette() {
+    let mut ppu = make_scroll_ppu();
+    let mut colors = vec![0; palette::EMPHASIZED_SIZE];
+    colors[0x30] = 0x123456;
+    ppu.set_palette(colors);
+
+    ppu.store(0x2000, 0x01);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0x0A);
+    render_frame(&mut ppu);
+    assert_eq!(0x123456, pixel(&ppu, 100, 100));
+}
+
+#[test]
+fn ppu_indexed_frame_conversions() {
+    let mut ppu = make_scroll_ppu();
+    write_vram(&mut ppu, 0x3F01, &[0x16]);
+    ppu.store(0x2000, 0x01);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2005, 0);
+    ppu.store(0x2001, 0xAA); // Red and blue emphasis
+    render_frame(&mut ppu);
+
+    let index = 0x5 << 6 | 0x16;
+    assert_eq!(index, ppu.frame()[100 * 256 + 100]);
+    let color = ppu.palette()[index as usize];
+    let (r, g, b) = ((color >> 16) as u8, (color >> 8) as u8, color as u8);
+
+    let mut rgb = vec![0; 256 * 240 * 3];
+    ppu.frame_rgb24(&mut rgb);
+    assert_eq!(&[r, g, b], &rgb[..3]);
+
+    let mut rgba = vec![0; 256 * 240 * 4];
+    ppu.frame_rgba32(&mut rgba);
+    assert_eq!(&[r, g, b, 0xFF], &rgba[rgba.len() - 4..]);
+
+    let mut bgra = vec![0; 256 * 240 * 4];
+    ppu.frame_bgra32(&mut bgra);
+    assert_eq!(&[b, g, r, 0xFF], &bgra[..4]);
+}
+
+fn ntsc_frame(preset: NtscPreset, frame: &[u16]) -> Vec<u32> {
+    let mut filter = NtscFilter::new(preset);
+    let mut out = vec![0; ntsc::OUTPUT_WIDTH * 240 * 3];
+    filter.filter(frame, &mut out);
+    out.chunks(3).map(|p| (p[0] as u32) << 16 | (p[1] as u32) << 8 | p[2] as u32).collect()
+}
+
+fn close_colors(a: u32, b: u32) -> bool {
+    (0..3).all(|i| {
+        let (a, b) = ((a >> (i * 8)) & 0xFF, (b >> (i * 8)) & 0xFF);
+        a.max(b) - a.min(b) <= 2
+    })
+}
+
+#[test]
+fn ntsc_filter_flat_colors() {
+    let colors = palette::generate(&palette::NtscSettings::new());
+    let center = 120 * ntsc::OUTPUT_WIDTH + 256;
+
+    let red = vec![0x16; 256 * 240];
+    let rgb = ntsc_frame(NtscPreset::Rgb, &red);
+    assert!(rgb.iter().all(|&c| c == colors[0x16]));
+    assert!(close_colors(colors[0x16], ntsc_frame(NtscPreset::SVideo, &red)[center]));
+
+    let gray = vec![0x10; 256 * 240];
+    assert!(close_colors(colors[0x10], ntsc_frame(NtscPreset::Composite, &gray)[center]));
+}
+
+#[test]
+fn ntsc_filter_artifacts() {
+    // One pixel wide black and white stripes
+    let stripes: Vec<u16> = (0..256 * 240).map(|i| if i % 2 == 0 { 0x0F } else { 0x30 }).collect();
+    let is_gray = |c: u32| close_colors(c, (c & 0xFF) * 0x010101);
+    let line = 120 * ntsc::OUTPUT_WIDTH;
+
+    // Composite mistakes the luma edges for chroma, S-Video keeps them apart
+    let composite = ntsc_frame(NtscPreset::Composite, &stripes);
+    assert!(composite[line + 16..line + 496].iter().any(|&c| !is_gray(c)));
+    let svideo = ntsc_frame(NtscPreset::SVideo, &stripes);
+    assert!(svideo[line + 16..line + 496].iter().all(|&c| is_gray(c)));
+
+    // The stripes blend together on composite
+    let luma = |c: &u32| {
+        let (r, g, b) = ((c >> 16) & 0xFF, (c >> 8) & 0xFF, c & 0xFF);
+        (299 * r + 587 * g + 114 * b) / 1000
+    };
+    let spread = |pixels: &[u32]| {
+        let values: Vec<u32> = pixels.iter().map(&luma).collect();
+        values.iter().max().unwrap() - values.iter().min().unwrap()
+    };
+    assert!(spread(&composite[line + 100..line + 110]) < spread(&svideo[line + 100..line + 110]));
+}
+
+fn image(width: usize, height: usize, pixels: &[u32]) -> Image {
+    Image { width, height, pixels: pixels.to_vec() }
+}
+
+#[test]
+fn nearest_and_scanlines() {
+    let source = image(2, 1, &[0xFF0000, 0x00FF00]);
+
+    let out = scale::nearest(&source, 3);
+    assert_eq!((6, 3), (out.width, out.height));
+    assert_eq!(0xFF0000, out.pixels[2 * 6 + 2]);
+    assert_eq!(0x00FF00, out.pixels[2 * 6 + 3]);
+
+    let out = scale::scanlines(&source, 2);
+    assert_eq!(&[0xFF0000, 0xFF0000, 0x00FF00, 0x00FF00], &out.pixels[..4]);
+    assert_eq!(&[0x7F0000, 0x7F0000, 0x007F00, 0x007F00], &out.pixels[4..]);
+}
+
+#[test]
+fn scale2x_and_scale3x_round_diagonals() {
+    const W: u32 = 0xFFFFFF;
+    // A diagonal line going down to the right
+    let source = image(3, 3, &[
+        W, 0, 0,
+        0, W, 0,
+        0, 0, W,
+    ]);
+
+    // The black pixels next to the line take its color towards it
+    let out = scale::scale2x(&source);
+    assert_eq!((6, 6), (out.width, out.height));
+    assert_eq!(W, out.pixels[2 * 6 + 1]);
+    assert_eq!(W, out.pixels[6 + 2]);
+    assert_eq!(0, out.pixels[2 * 6]);
+    assert!(out.pixels[2 * 6 + 2..2 * 6 + 4].iter().all(|&p| p == W));
+
+    // A lone corner gets rounded
+    let corner = scale::scale2x(&image(2, 2, &[W, 0, 0, 0]));
+    assert_eq!(W, corner.pixels[0]);
+    assert_eq!(0, corner.pixels[4 + 1]);
+
+    let out = scale::scale3x(&source);
+    assert_eq!((9, 9), (out.width, out.height));
+    assert_eq!(W, out.pixels[3 * 9 + 1]);
+    assert_eq!(W, out.pixels[3 * 9 + 2]);
+    assert_eq!(0, out.pixels[3 * 9]);
+    assert_eq!(W, out.pixels[4 * 9 + 4]);
+
+    // Flat areas stay flat
+    let flat = scale::scale3x(&image(2, 2, &[W; 4]));
+    assert!(flat.pixels.iter().all(|&p| p == W));
+}
+
+#[test]
+fn hq2x_smooths_edges() {
+    const W: u32 = 0xFFFFFF;
+    let source = image(3, 3, &[
+        W, 0, 0,
+        0, W, 0,
+        0, 0, W,
+    ]);
+    let out = scale::hq2x(&source);
+    assert_eq!((6, 6), (out.width, out.height));
+
+    // Corners of the center pixel facing the black sides are blended (2:1:1 with
+    // both black edges), the ones the line goes through aren't
+    assert_eq!(0x7F7F7F, out.pixels[2 * 6 + 3]);
+    assert_eq!(0x7F7F7F, out.pixels[3 * 6 + 2]);
+    assert_eq!(W, out.pixels[2 * 6 + 2]);
+    assert_eq!(W, out.pixels[3 * 6 + 3]);
+
+    let flat = scale::hq2x(&image(2, 2, &[0x123456; 4]));
+    assert!(flat.pixels.iter().all(|&p| p == 0x123456));
+}
+
+#[test]
+fn resize_blends_only_across_boundaries() {
+    const W: u32 = 0xFFFFFF;
+
+    // 2 pixels into 3, the middle one straddles both
+    let out = scale::resize(&image(2, 1, &[0, W]), 3, 1);
+    assert_eq!(vec![0, 0x7F7F7F, W], out.pixels);
+
+    // 8:7, the edge gets 4/7 of the white pixel and 3/7 of the black one
+    let out = scale::resize(&image(7, 1, &[W, W, W, W, 0, 0, 0]), 8, 2);
+    assert_eq!(vec![W, W, W, W, 0x919191, 0, 0, 0], out.pixels[..8].to_vec());
+    assert_eq!(out.pixels[..8], out.pixels[8..]);
+
+    // Shrinking averages
+    let out = scale::resize(&image(4, 2, &[0, W, W, W, 0, W, W, W]), 2, 1);
+    assert_eq!(vec![0x7F7F7F, W], out.pixels);
+}
+
+#[test]
+fn scale_filter_factors_and_aspect() {
+    assert_eq!(Some(ScaleFilter::Hq2x), ScaleFilter::parse("hq2x"));
+    assert_eq!(None, ScaleFilter::parse("hq4x"));
+    assert_eq!(4, ScaleFilter::Nearest.factor(4));
+    assert_eq!(2, ScaleFilter::Scanlines.factor(1));
+    assert_eq!(3, ScaleFilter::Scale3x.factor(2));
+    assert_eq!(2, ScaleFilter::Hq2x.factor(3));
+    assert_eq!(6, ScaleFilter::Scale3x.factor(7));
+
+    // Pattern filters are followed by nearest neighbor up to the scale
+    let source = image(3, 3, &[0, 0, 0, 0, 0xFFFFFF, 0, 0, 0, 0]);
+    let out = ScaleFilter::Scale2x.apply(&source, 4);
+    assert_eq!((12, 12), (out.width, out.height));
+    assert_eq!(scale::nearest(&scale::scale2x(&source), 2).pixels, out.pixels);
+
+    assert_eq!(293, scale::aspect_width(1));
+    assert_eq!(585, scale::aspect_width(2));
+}
+
+#[test]
+fn ntsc_filter_rgb_palette() {
+    let frame = vec![0x16; 256 * 240];
+    let mut out = vec![0; ntsc::OUTPUT_WIDTH * 240 * 3];
+
+    let mut filter = NtscFilter::new(NtscPreset::Rgb);
+    let mut colors = vec![0; palette::EMPHASIZED_SIZE];
+    colors[0x16] = 0x123456;
+    filter.set_palette(colors);
+    filter.filter(&frame, &mut out);
+    assert_eq!(&[0x12, 0x34, 0x56, 0x12, 0x34, 0x56], &out[..6]);
+
+    // New settings bring back generated colors
+    let mut settings = palette::NtscSettings::new();
+    settings.saturation = 0.0;
+    filter.set_settings(settings);
+    filter.filter(&frame, &mut out);
+    assert_eq!(out[0], out[1]);
+    assert_eq!(out[1], out[2]);
 }